@@ -1,14 +1,13 @@
 use libc::{
-    __errno_location, FILE, c_char, c_int, c_uint, gid_t, mode_t, off_t, size_t, ssize_t, uid_t,
+    __errno_location, FILE, c_char, c_int, c_long, c_uint, gid_t, mode_t, off_t, size_t, ssize_t,
+    uid_t,
 };
 use regex::Regex;
-use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::env;
 use std::ffi::{CStr, CString};
 use std::fs;
-use std::path::Path;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::{Mutex, OnceLock};
 
 struct Blacklist {
@@ -38,26 +37,27 @@ impl Blacklist {
         false
     }
 }
-thread_local! {
-    static DIRENT_BUFFER: RefCell<libc::dirent> = RefCell::new(unsafe { std::mem::zeroed() });
-}
-
-thread_local! {
-    static DIRENT64_BUFFER: RefCell<libc::dirent64> = RefCell::new(unsafe { std::mem::zeroed() });
-}
-
-thread_local! {
-    static OVERLAY_DISABLED: RefCell<bool> = RefCell::new(false);
-}
-
 #[derive(Debug, PartialEq, Eq, Hash)]
 struct DirPointer(*mut libc::DIR);
 unsafe impl Send for DirPointer {}
 unsafe impl Sync for DirPointer {}
+/// One name in a merged directory listing, taken from the highest-priority layer that has it.
+struct MergedEntry {
+    name: Vec<u8>,
+    ino: u64,
+    d_type: u8,
+}
+
+/// A merged directory stream. `handle` is the real stream handed to the caller; the listing of
+/// every layer of `path` is read up front into `entries`, and each stream owns the dirent buffers
+/// it returns. `position` doubles as the `telldir` cookie.
 struct OverlayDir {
-    original_dir_ptr: *mut libc::DIR,
-    overlay_dir_ptrs: Vec<*mut libc::DIR>,
-    seen_original_entries: HashSet<String>,
+    handle: *mut libc::DIR,
+    path: String,
+    entries: Vec<MergedEntry>,
+    position: usize,
+    dirent: Box<libc::dirent>,
+    dirent64: Box<libc::dirent64>,
 }
 
 unsafe impl Send for OverlayDir {}
@@ -73,25 +73,81 @@ fn get_overlay_dir_map() -> &'static Mutex<HashMap<DirPointer, Box<OverlayDir>>>
     }
     map
 }
+/// Virtual path of an fd, plus the identity of the file it was recorded for so a recycled fd
+/// number is never mistaken for it.
+#[derive(Clone)]
+struct FdPath {
+    path: String,
+    dev: libc::dev_t,
+    ino: libc::ino_t,
+}
+
+/// A merged directory listing served to raw `getdents64` calls on a directory fd, with the
+/// identity of the directory so a reused fd number starts a fresh listing.
+struct FdListing {
+    dev: libc::dev_t,
+    ino: libc::ino_t,
+    entries: Vec<MergedEntry>,
+    position: usize,
+}
+
 static VERBOSE_MODE: OnceLock<bool> = OnceLock::new();
 fn is_verbose_mode_enabled() -> bool {
     *VERBOSE_MODE
         .get_or_init(|| env::var("OBSIDIANOS_OVERLAYS_VERBOSE").map_or(false, |v| v == "1"))
 }
+static PHYSICAL_DOTDOT: OnceLock<bool> = OnceLock::new();
+fn is_physical_dotdot_enabled() -> bool {
+    *PHYSICAL_DOTDOT
+        .get_or_init(|| env::var("OBSIDIANOS_OVERLAYS_PHYSICAL_DOTDOT").is_ok_and(|v| v == "1"))
+}
+static VIRTUAL_INODES: OnceLock<bool> = OnceLock::new();
+fn is_virtual_inodes_enabled() -> bool {
+    *VIRTUAL_INODES
+        .get_or_init(|| env::var("OBSIDIANOS_OVERLAYS_VIRTUAL_INODES").is_ok_and(|v| v == "1"))
+}
+static ALLOW_DEVICES: OnceLock<bool> = OnceLock::new();
+fn is_allow_devices_enabled() -> bool {
+    *ALLOW_DEVICES
+        .get_or_init(|| env::var("OBSIDIANOS_OVERLAYS_ALLOW_DEVICES").is_ok_and(|v| v == "1"))
+}
+static EPHEMERAL_MODE: OnceLock<bool> = OnceLock::new();
+fn is_ephemeral_mode_enabled() -> bool {
+    *EPHEMERAL_MODE
+        .get_or_init(|| env::var("OBSIDIANOS_OVERLAYS_EPHEMERAL").is_ok_and(|v| v == "1"))
+}
 static OVERLAY_CONFIG: OnceLock<Mutex<Vec<String>>> = OnceLock::new();
+static UPPER_DIR: OnceLock<Option<String>> = OnceLock::new();
+static EPHEMERAL_DIR: OnceLock<Option<String>> = OnceLock::new();
+static READ_ONLY_PREFIXES: OnceLock<Vec<String>> = OnceLock::new();
+static EPHEMERAL_OWNER_PID: AtomicU32 = AtomicU32::new(0);
+/// Numbers the temporary files of copy-ups so concurrent ones in a process never share a name.
+static COPY_UP_COUNTER: AtomicU64 = AtomicU64::new(0);
+/// Virtual working directory, and whether the kernel's one lies inside an overlay.
+static CURRENT_DIR: OnceLock<Mutex<Option<(String, bool)>>> = OnceLock::new();
+static FD_PATHS: OnceLock<Mutex<HashMap<c_int, FdPath>>> = OnceLock::new();
+static FD_LISTINGS: OnceLock<Mutex<HashMap<c_int, FdListing>>> = OnceLock::new();
 static BLACKLIST: OnceLock<Mutex<Blacklist>> = OnceLock::new();
 static ORIG_FUNCS: OnceLock<OriginalFunctions> = OnceLock::new();
 static INIT_GUARD: AtomicBool = AtomicBool::new(false);
+static OPENAT2_SUPPORTED: OnceLock<bool> = OnceLock::new();
 struct OriginalFunctions {
     open: unsafe extern "C" fn(*const c_char, c_int, ...) -> c_int,
     open64: unsafe extern "C" fn(*const c_char, c_int, ...) -> c_int,
     openat: unsafe extern "C" fn(c_int, *const c_char, c_int, ...) -> c_int,
     openat64: unsafe extern "C" fn(c_int, *const c_char, c_int, ...) -> c_int,
+    open_2: unsafe extern "C" fn(*const c_char, c_int) -> c_int,
+    open64_2: unsafe extern "C" fn(*const c_char, c_int) -> c_int,
+    openat_2: unsafe extern "C" fn(c_int, *const c_char, c_int) -> c_int,
+    openat64_2: unsafe extern "C" fn(c_int, *const c_char, c_int) -> c_int,
     fopen: unsafe extern "C" fn(*const c_char, *const c_char) -> *mut FILE,
     fopen64: unsafe extern "C" fn(*const c_char, *const c_char) -> *mut FILE,
     stat: unsafe extern "C" fn(*const c_char, *mut libc::stat) -> c_int,
     lstat: unsafe extern "C" fn(*const c_char, *mut libc::stat) -> c_int,
     fstatat: unsafe extern "C" fn(c_int, *const c_char, *mut libc::stat, c_int) -> c_int,
+    fstatat64: unsafe extern "C" fn(c_int, *const c_char, *mut libc::stat64, c_int) -> c_int,
+    fstat: unsafe extern "C" fn(c_int, *mut libc::stat) -> c_int,
+    fstat64: unsafe extern "C" fn(c_int, *mut libc::stat64) -> c_int,
     stat64: unsafe extern "C" fn(*const c_char, *mut libc::stat64) -> c_int,
     lstat64: unsafe extern "C" fn(*const c_char, *mut libc::stat64) -> c_int,
     access: unsafe extern "C" fn(*const c_char, c_int) -> c_int,
@@ -101,6 +157,8 @@ struct OriginalFunctions {
     execve:
         unsafe extern "C" fn(*const c_char, *const *const c_char, *const *const c_char) -> c_int,
     execvp: unsafe extern "C" fn(*const c_char, *const *const c_char) -> c_int,
+    execvpe:
+        unsafe extern "C" fn(*const c_char, *const *const c_char, *const *const c_char) -> c_int,
     execv: unsafe extern "C" fn(*const c_char, *const *const c_char) -> c_int,
     unlink: unsafe extern "C" fn(*const c_char) -> c_int,
     unlinkat: unsafe extern "C" fn(c_int, *const c_char, c_int) -> c_int,
@@ -109,9 +167,11 @@ struct OriginalFunctions {
     mkdirat: unsafe extern "C" fn(c_int, *const c_char, mode_t) -> c_int,
     rename: unsafe extern "C" fn(*const c_char, *const c_char) -> c_int,
     renameat: unsafe extern "C" fn(c_int, *const c_char, c_int, *const c_char) -> c_int,
+    renameat2: unsafe extern "C" fn(c_int, *const c_char, c_int, *const c_char, c_uint) -> c_int,
     creat: unsafe extern "C" fn(*const c_char, mode_t) -> c_int,
     creat64: unsafe extern "C" fn(*const c_char, mode_t) -> c_int,
     chdir: unsafe extern "C" fn(*const c_char) -> c_int,
+    fchdir: unsafe extern "C" fn(c_int) -> c_int,
     chmod: unsafe extern "C" fn(*const c_char, mode_t) -> c_int,
     fchmodat: unsafe extern "C" fn(c_int, *const c_char, mode_t, c_int) -> c_int,
     chown: unsafe extern "C" fn(*const c_char, uid_t, gid_t) -> c_int,
@@ -126,9 +186,59 @@ struct OriginalFunctions {
     readdir64: unsafe extern "C" fn(*mut libc::DIR) -> *mut libc::dirent64,
     opendir: unsafe extern "C" fn(*const c_char) -> *mut libc::DIR,
     closedir: unsafe extern "C" fn(*mut libc::DIR) -> c_int,
+    fdopendir: unsafe extern "C" fn(c_int) -> *mut libc::DIR,
+    dirfd: unsafe extern "C" fn(*mut libc::DIR) -> c_int,
+    rewinddir: unsafe extern "C" fn(*mut libc::DIR),
+    telldir: unsafe extern "C" fn(*mut libc::DIR) -> libc::c_long,
+    seekdir: unsafe extern "C" fn(*mut libc::DIR, libc::c_long),
+    close: unsafe extern "C" fn(c_int) -> c_int,
+    dup: unsafe extern "C" fn(c_int) -> c_int,
+    dup2: unsafe extern "C" fn(c_int, c_int) -> c_int,
+    dup3: unsafe extern "C" fn(c_int, c_int, c_int) -> c_int,
+    getxattr: unsafe extern "C" fn(*const c_char, *const c_char, *mut libc::c_void, size_t) -> ssize_t,
+    lgetxattr: unsafe extern "C" fn(*const c_char, *const c_char, *mut libc::c_void, size_t) -> ssize_t,
+    fgetxattr: unsafe extern "C" fn(c_int, *const c_char, *mut libc::c_void, size_t) -> ssize_t,
+    setxattr: unsafe extern "C" fn(
+        *const c_char,
+        *const c_char,
+        *const libc::c_void,
+        size_t,
+        c_int,
+    ) -> c_int,
+    lsetxattr: unsafe extern "C" fn(
+        *const c_char,
+        *const c_char,
+        *const libc::c_void,
+        size_t,
+        c_int,
+    ) -> c_int,
+    fsetxattr: unsafe extern "C" fn(c_int, *const c_char, *const libc::c_void, size_t, c_int) -> c_int,
+    listxattr: unsafe extern "C" fn(*const c_char, *mut c_char, size_t) -> ssize_t,
+    llistxattr: unsafe extern "C" fn(*const c_char, *mut c_char, size_t) -> ssize_t,
+    flistxattr: unsafe extern "C" fn(c_int, *mut c_char, size_t) -> ssize_t,
+    removexattr: unsafe extern "C" fn(*const c_char, *const c_char) -> c_int,
+    lremovexattr: unsafe extern "C" fn(*const c_char, *const c_char) -> c_int,
+    fremovexattr: unsafe extern "C" fn(c_int, *const c_char) -> c_int,
+    utimensat: unsafe extern "C" fn(c_int, *const c_char, *const libc::timespec, c_int) -> c_int,
+    futimesat: unsafe extern "C" fn(c_int, *const c_char, *const libc::timeval) -> c_int,
+    utimes: unsafe extern "C" fn(*const c_char, *const libc::timeval) -> c_int,
+    utime: unsafe extern "C" fn(*const c_char, *const libc::utimbuf) -> c_int,
+    mknod: unsafe extern "C" fn(*const c_char, mode_t, libc::dev_t) -> c_int,
+    mknodat: unsafe extern "C" fn(c_int, *const c_char, mode_t, libc::dev_t) -> c_int,
+    mkfifo: unsafe extern "C" fn(*const c_char, mode_t) -> c_int,
+    mkfifoat: unsafe extern "C" fn(c_int, *const c_char, mode_t) -> c_int,
+    connect: unsafe extern "C" fn(c_int, *const libc::sockaddr, libc::socklen_t) -> c_int,
+    getcwd: unsafe extern "C" fn(*mut c_char, size_t) -> *mut c_char,
+    get_current_dir_name: unsafe extern "C" fn() -> *mut c_char,
+    realpath: unsafe extern "C" fn(*const c_char, *mut c_char) -> *mut c_char,
+    realpath_chk: unsafe extern "C" fn(*const c_char, *mut c_char, size_t) -> *mut c_char,
+    canonicalize_file_name: unsafe extern "C" fn(*const c_char) -> *mut c_char,
+    syscall: unsafe extern "C" fn(c_long, ...) -> c_long,
     statx: Option<
         unsafe extern "C" fn(c_int, *const c_char, c_int, c_uint, *mut libc::statx) -> c_int,
     >,
+    openat2:
+        Option<unsafe extern "C" fn(c_int, *const c_char, *mut libc::open_how, size_t) -> c_int>,
 }
 
 fn load_overlay_config() -> Vec<String> {
@@ -154,6 +264,209 @@ fn load_overlay_config() -> Vec<String> {
     result
 }
 
+/// Reads the writable upper layer from `/etc/obsidianos-overlays.upper`: the first path in the
+/// file, with the same comment rules as the overlay config.
+fn load_upper_dir() -> Option<String> {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return None;
+    }
+    INIT_GUARD.store(true, Ordering::Relaxed);
+    let result = fs::read_to_string("/etc/obsidianos-overlays.upper")
+        .ok()
+        .and_then(|content| {
+            content
+                .lines()
+                .map(|line| {
+                    line.split_once('#')
+                        .map_or(line, |(before_comment, _)| before_comment)
+                        .trim()
+                        .trim_end_matches('/')
+                })
+                .find(|line| !line.is_empty())
+                .map(String::from)
+        });
+    INIT_GUARD.store(false, Ordering::Relaxed);
+    result
+}
+
+/// Reads the read-only prefixes from `/etc/obsidianos-overlays.readonly`, one path per line, with
+/// the same comment rules as the overlay config.
+fn load_read_only_prefixes() -> Vec<String> {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return Vec::new();
+    }
+    INIT_GUARD.store(true, Ordering::Relaxed);
+    let result = match fs::read_to_string("/etc/obsidianos-overlays.readonly") {
+        Ok(content) => content
+            .lines()
+            .map(|line| {
+                line.split_once('#')
+                    .map_or(line, |(before_comment, _)| before_comment)
+                    .trim()
+            })
+            .filter(|line| !line.is_empty())
+            // `/` becomes the empty prefix, which covers every absolute path.
+            .map(|line| line.trim_end_matches('/').to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    INIT_GUARD.store(false, Ordering::Relaxed);
+    result
+}
+
+/// Whether modifying `path` would change the base filesystem or an overlay under a read-only
+/// prefix. Changes that go to the upper layer are always allowed; blacklisted paths never do.
+fn is_read_only(path: &str) -> bool {
+    let read_only = READ_ONLY_PREFIXES
+        .get_or_init(load_read_only_prefixes)
+        .iter()
+        .any(|prefix| {
+            path.strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        });
+    read_only
+        && (get_upper_dir().is_none() || get_blacklist().lock().unwrap().is_blacklisted(path))
+}
+
+/// The errno a change to the read-only `path` fails with. Like on a read-only mount, a missing
+/// file is still reported as missing and an existing one blocks creating it.
+fn read_only_errno(path: &str, creating: bool) -> c_int {
+    match visible_source(path) {
+        Ok(_) if creating => libc::EEXIST,
+        Ok(_) => libc::EROFS,
+        Err(_) if creating => libc::EROFS,
+        Err(errno) => errno,
+    }
+}
+
+/// Environment variables that hand the ephemeral layer of a session down to its child processes.
+const EPHEMERAL_DIR_ENV: &str = "OBSIDIANOS_OVERLAYS_EPHEMERAL_DIR";
+const EPHEMERAL_OWNER_ENV: &str = "OBSIDIANOS_OVERLAYS_EPHEMERAL_OWNER";
+/// Virtual path of the program a redirected exec started, for `/proc/self/exe`.
+const EXEC_PATH_ENV: &str = "OBSIDIANOS_OVERLAYS_EXEC_PATH";
+/// Name prefix of ephemeral layers in the temporary directory, followed by the pid of the
+/// session's root process.
+const EPHEMERAL_DIR_PREFIX: &str = "obsidianos-overlays.";
+
+/// Returns the session's ephemeral layer. The session's root process creates it in the temporary
+/// directory and publishes it through the environment, so every process started below it writes to
+/// the same layer.
+fn load_ephemeral_dir() -> Option<String> {
+    if let Ok(dir) = env::var(EPHEMERAL_DIR_ENV) {
+        // The root process keeps owning the layer across an exec.
+        let owner = env::var(EPHEMERAL_OWNER_ENV).ok();
+        if owner.is_some_and(|pid| pid == std::process::id().to_string()) {
+            remove_ephemeral_dir_at_exit();
+        }
+        return Some(dir);
+    }
+    if !is_ephemeral_mode_enabled() {
+        return None;
+    }
+    INIT_GUARD.store(true, Ordering::Relaxed);
+    sweep_stale_ephemeral_dirs(&env::temp_dir());
+    INIT_GUARD.store(false, Ordering::Relaxed);
+    let template = env::temp_dir().join(format!(
+        "{}{}.XXXXXX",
+        EPHEMERAL_DIR_PREFIX,
+        std::process::id()
+    ));
+    let template_cstr = CString::new(template.to_string_lossy().as_bytes()).ok()?;
+    let mut template_bytes = template_cstr.into_bytes_with_nul();
+    if unsafe { libc::mkdtemp(template_bytes.as_mut_ptr() as *mut c_char) }.is_null() {
+        eprintln!(
+            "[*] ObsidianOS Overlays: cannot create the ephemeral layer: {}",
+            std::io::Error::last_os_error()
+        );
+        return None;
+    }
+    template_bytes.pop();
+    let dir = String::from_utf8_lossy(&template_bytes).into_owned();
+    unsafe {
+        env::set_var(EPHEMERAL_DIR_ENV, &dir);
+        env::set_var(EPHEMERAL_OWNER_ENV, std::process::id().to_string());
+    }
+    remove_ephemeral_dir_at_exit();
+    if is_verbose_mode_enabled() {
+        eprintln!("[*] ObsidianOS Overlays: ephemeral layer {}", dir);
+    }
+    Some(dir)
+}
+
+/// Removes the ephemeral layers in `tmp` that earlier sessions of this user left behind because
+/// their root process ended without running its `atexit` handler, after `_exit` or a fatal
+/// signal. A layer whose root process is still running is kept.
+fn sweep_stale_ephemeral_dirs(tmp: &std::path::Path) {
+    use std::os::unix::fs::MetadataExt;
+    let Ok(entries) = fs::read_dir(tmp) else {
+        return;
+    };
+    let uid = unsafe { libc::getuid() };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(pid) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(EPHEMERAL_DIR_PREFIX))
+            .and_then(|rest| rest.split_once('.'))
+            .and_then(|(pid, _)| pid.parse::<libc::pid_t>().ok())
+            .filter(|&pid| pid > 0)
+        else {
+            continue;
+        };
+        let owned = entry
+            .path()
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.is_dir() && metadata.uid() == uid);
+        if !owned || unsafe { libc::kill(pid, 0) } == 0 || last_errno() != libc::ESRCH {
+            continue;
+        }
+        if fs::remove_dir_all(entry.path()).is_ok() && is_verbose_mode_enabled() {
+            eprintln!(
+                "[*] ObsidianOS Overlays: removed stale ephemeral layer {}",
+                entry.path().display()
+            );
+        }
+    }
+}
+
+fn remove_ephemeral_dir_at_exit() {
+    EPHEMERAL_OWNER_PID.store(std::process::id(), Ordering::Relaxed);
+    unsafe { libc::atexit(remove_ephemeral_dir) };
+}
+
+/// `atexit` handler of the session's root process. Forked children inherit the handler, so it
+/// only acts in the process that owns the layer.
+extern "C" fn remove_ephemeral_dir() {
+    if EPHEMERAL_OWNER_PID.load(Ordering::Relaxed) != std::process::id() {
+        return;
+    }
+    if let Some(Some(dir)) = EPHEMERAL_DIR.get() {
+        INIT_GUARD.store(true, Ordering::Relaxed);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
+
+fn get_ephemeral_dir() -> Option<String> {
+    EPHEMERAL_DIR.get_or_init(load_ephemeral_dir).clone()
+}
+
+/// Sets up the ephemeral layer as soon as the library is loaded, before the program gets a chance
+/// to change its environment.
+#[used]
+#[unsafe(link_section = ".init_array")]
+static INIT_EPHEMERAL_DIR: extern "C" fn() = init_ephemeral_dir;
+
+extern "C" fn init_ephemeral_dir() {
+    get_ephemeral_dir();
+}
+
+/// Returns the writable upper layer: the session's ephemeral layer in ephemeral mode, otherwise
+/// the configured one, if any. It is also the highest-priority layer in `get_overlay_config`, so
+/// everything written there is visible right away.
+fn get_upper_dir() -> Option<String> {
+    get_ephemeral_dir().or_else(|| UPPER_DIR.get_or_init(load_upper_dir).clone())
+}
+
 fn get_overlay_config() -> Vec<String> {
     if let Some(config_mutex) = OVERLAY_CONFIG.get() {
         if is_verbose_mode_enabled() {
@@ -173,7 +486,12 @@ fn get_overlay_config() -> Vec<String> {
     if is_verbose_mode_enabled() {
         eprintln!("[DEBUG] get_overlay_config: OVERLAY_CONFIG not initialized, loading config...");
     }
-    let loaded_config = load_overlay_config();
+    // In ephemeral mode the configured upper layer stays visible, read-only, below the session's.
+    let mut loaded_config: Vec<String> = get_ephemeral_dir()
+        .into_iter()
+        .chain(UPPER_DIR.get_or_init(load_upper_dir).clone())
+        .collect();
+    loaded_config.extend(load_overlay_config());
     if is_verbose_mode_enabled() {
         eprintln!("[DEBUG] get_overlay_config: acquiring lock for initialization...");
     }
@@ -293,16 +611,25 @@ fn get_original_functions() -> &'static OriginalFunctions {
         let stat_ptr = dlsym("stat");
         assert!(!stat_ptr.is_null(), "Failed to load critical symbol: stat");
         let statx_ptr = dlsym("statx");
+        // glibc only wraps openat2 since 2.42; older ones reach it through syscall().
+        let openat2_ptr = libc::dlsym(libc::RTLD_NEXT, c"openat2".as_ptr());
         OriginalFunctions {
             open: std::mem::transmute(open_ptr),
             open64: std::mem::transmute(dlsym("open64")),
             openat: std::mem::transmute(dlsym("openat")),
             openat64: std::mem::transmute(dlsym("openat64")),
+            open_2: std::mem::transmute(dlsym("__open_2")),
+            open64_2: std::mem::transmute(dlsym("__open64_2")),
+            openat_2: std::mem::transmute(dlsym("__openat_2")),
+            openat64_2: std::mem::transmute(dlsym("__openat64_2")),
             fopen: std::mem::transmute(dlsym("fopen")),
             fopen64: std::mem::transmute(dlsym("fopen64")),
             stat: std::mem::transmute(stat_ptr),
             lstat: std::mem::transmute(dlsym("lstat")),
             fstatat: std::mem::transmute(dlsym("fstatat")),
+            fstatat64: std::mem::transmute(dlsym("fstatat64")),
+            fstat: std::mem::transmute(dlsym("fstat")),
+            fstat64: std::mem::transmute(dlsym("fstat64")),
             stat64: std::mem::transmute(dlsym("stat64")),
             lstat64: std::mem::transmute(dlsym("lstat64")),
             access: std::mem::transmute(dlsym("access")),
@@ -311,6 +638,7 @@ fn get_original_functions() -> &'static OriginalFunctions {
             readlinkat: std::mem::transmute(dlsym("readlinkat")),
             execve: std::mem::transmute(dlsym("execve")),
             execvp: std::mem::transmute(dlsym("execvp")),
+            execvpe: std::mem::transmute(dlsym("execvpe")),
             execv: std::mem::transmute(dlsym("execv")),
             unlink: std::mem::transmute(dlsym("unlink")),
             unlinkat: std::mem::transmute(dlsym("unlinkat")),
@@ -319,9 +647,11 @@ fn get_original_functions() -> &'static OriginalFunctions {
             mkdirat: std::mem::transmute(dlsym("mkdirat")),
             rename: std::mem::transmute(dlsym("rename")),
             renameat: std::mem::transmute(dlsym("renameat")),
+            renameat2: std::mem::transmute(dlsym("renameat2")),
             creat: std::mem::transmute(dlsym("creat")),
             creat64: std::mem::transmute(dlsym("creat64")),
             chdir: std::mem::transmute(dlsym("chdir")),
+            fchdir: std::mem::transmute(dlsym("fchdir")),
             chmod: std::mem::transmute(dlsym("chmod")),
             fchmodat: std::mem::transmute(dlsym("fchmodat")),
             chown: std::mem::transmute(dlsym("chown")),
@@ -336,139 +666,437 @@ fn get_original_functions() -> &'static OriginalFunctions {
             readdir64: std::mem::transmute(dlsym("readdir64")),
             opendir: std::mem::transmute(dlsym("opendir")),
             closedir: std::mem::transmute(dlsym("closedir")),
+            fdopendir: std::mem::transmute(dlsym("fdopendir")),
+            dirfd: std::mem::transmute(dlsym("dirfd")),
+            rewinddir: std::mem::transmute(dlsym("rewinddir")),
+            telldir: std::mem::transmute(dlsym("telldir")),
+            seekdir: std::mem::transmute(dlsym("seekdir")),
+            close: std::mem::transmute(dlsym("close")),
+            dup: std::mem::transmute(dlsym("dup")),
+            dup2: std::mem::transmute(dlsym("dup2")),
+            dup3: std::mem::transmute(dlsym("dup3")),
+            getxattr: std::mem::transmute(dlsym("getxattr")),
+            lgetxattr: std::mem::transmute(dlsym("lgetxattr")),
+            fgetxattr: std::mem::transmute(dlsym("fgetxattr")),
+            setxattr: std::mem::transmute(dlsym("setxattr")),
+            lsetxattr: std::mem::transmute(dlsym("lsetxattr")),
+            fsetxattr: std::mem::transmute(dlsym("fsetxattr")),
+            listxattr: std::mem::transmute(dlsym("listxattr")),
+            llistxattr: std::mem::transmute(dlsym("llistxattr")),
+            flistxattr: std::mem::transmute(dlsym("flistxattr")),
+            removexattr: std::mem::transmute(dlsym("removexattr")),
+            lremovexattr: std::mem::transmute(dlsym("lremovexattr")),
+            fremovexattr: std::mem::transmute(dlsym("fremovexattr")),
+            utimensat: std::mem::transmute(dlsym("utimensat")),
+            futimesat: std::mem::transmute(dlsym("futimesat")),
+            utimes: std::mem::transmute(dlsym("utimes")),
+            utime: std::mem::transmute(dlsym("utime")),
+            mknod: std::mem::transmute(dlsym("mknod")),
+            mknodat: std::mem::transmute(dlsym("mknodat")),
+            mkfifo: std::mem::transmute(dlsym("mkfifo")),
+            mkfifoat: std::mem::transmute(dlsym("mkfifoat")),
+            connect: std::mem::transmute(dlsym("connect")),
+            getcwd: std::mem::transmute(dlsym("getcwd")),
+            get_current_dir_name: std::mem::transmute(dlsym("get_current_dir_name")),
+            realpath: std::mem::transmute(dlsym("realpath")),
+            realpath_chk: std::mem::transmute(dlsym("__realpath_chk")),
+            canonicalize_file_name: std::mem::transmute(dlsym("canonicalize_file_name")),
+            syscall: std::mem::transmute(dlsym("syscall")),
             statx: if statx_ptr.is_null() {
                 None
             } else {
                 Some(std::mem::transmute(statx_ptr))
             },
+            openat2: if openat2_ptr.is_null() {
+                None
+            } else {
+                Some(std::mem::transmute(openat2_ptr))
+            },
         }
     })
 }
 
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn statx(
-    dirfd: c_int,
-    pathname: *const c_char,
-    flags: c_int,
-    mask: c_uint,
-    statxbuf: *mut libc::statx,
-) -> c_int {
-    if INIT_GUARD.load(Ordering::Relaxed) {
-        let original_functions = get_original_functions();
-        if let Some(original_statx) = original_functions.statx {
-            return unsafe { original_statx(dirfd, pathname, flags, mask, statxbuf) };
-        } else {
-            unsafe {
-                *__errno_location() = libc::ENOSYS;
-            }
-            return -1;
+/// Result of looking a path up in the overlays, highest priority first.
+enum OverlayLookup {
+    /// Served from this physical path inside an overlay.
+    Found(String),
+    /// Hidden by a whiteout or an opaque directory, so it must look like it does not exist.
+    Hidden,
+    /// Not overlaid; the base filesystem answers.
+    Base,
+}
+
+impl OverlayLookup {
+    fn or_else(self, f: impl FnOnce() -> OverlayLookup) -> OverlayLookup {
+        match self {
+            OverlayLookup::Base => f(),
+            found_or_hidden => found_or_hidden,
         }
     }
+}
 
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            let original_functions = get_original_functions();
-            if let Some(original_statx) = original_functions.statx {
-                return unsafe { original_statx(dirfd, pathname, flags, mask, statxbuf) };
-            } else {
-                unsafe {
-                    *__errno_location() = libc::ENOSYS;
-                }
-                return -1;
-            }
-        }
+/// Where an interposer should send a path-taking call.
+enum Resolved {
+    /// Pass the caller's arguments through unchanged.
+    Original,
+    /// Use this absolute path instead, which makes any dirfd irrelevant.
+    Redirect(CString),
+    /// Fail with this errno without calling through.
+    Error(c_int),
+}
+
+/// Resolves `pathname` for `dirfd` against the blacklist and the overlays, following a final
+/// symlink.
+unsafe fn resolve_path_at(dirfd: c_int, pathname: *const c_char) -> Resolved {
+    unsafe { resolve_lookup_at(dirfd, pathname, true) }
+}
+
+unsafe fn resolve_path(pathname: *const c_char) -> Resolved {
+    unsafe { resolve_path_at(libc::AT_FDCWD, pathname) }
+}
+
+/// Like `resolve_path_at`, but a final symlink is resolved to the link itself.
+unsafe fn resolve_link_path_at(dirfd: c_int, pathname: *const c_char) -> Resolved {
+    unsafe { resolve_lookup_at(dirfd, pathname, false) }
+}
+
+/// Resolves `pathname` to the entry `stat` reports on, following a final symlink if `follow`.
+/// Unlike `resolve_lookup_at`, a directory that several layers merge into is served by the topmost
+/// one.
+unsafe fn resolve_entry_path(pathname: *const c_char, follow: bool) -> Resolved {
+    let Some(path_str) = (unsafe { virtual_path(pathname) }) else {
+        return Resolved::Original;
+    };
+    let follow = follow || unsafe { has_trailing_slash(pathname) };
+    let target = match resolve_overlay_symlinks(&path_str, follow) {
+        Ok(target) => target,
+        Err(errno) => return Resolved::Error(errno),
+    };
+    if get_blacklist().lock().unwrap().is_blacklisted(&target) {
+        return unsafe { base_resolved(libc::AT_FDCWD, pathname, &path_str, target) };
+    }
+    match find_overlay_entry(&target) {
+        OverlayLookup::Found(overlay_path) => Resolved::Redirect(CString::new(overlay_path).unwrap()),
+        OverlayLookup::Hidden => Resolved::Error(libc::ENOENT),
+        OverlayLookup::Base => unsafe { base_resolved(libc::AT_FDCWD, pathname, &path_str, target) },
     }
+}
 
-    if OVERLAY_DISABLED.with(|disabled| *disabled.borrow()) {
-        let original_functions = get_original_functions();
-        if let Some(original_statx) = original_functions.statx {
-            return unsafe { original_statx(dirfd, pathname, flags, mask, statxbuf) };
+unsafe fn resolve_lookup_at(dirfd: c_int, pathname: *const c_char, follow: bool) -> Resolved {
+    let Some(path_str) = (unsafe { virtual_path_at(dirfd, pathname) }) else {
+        return Resolved::Original;
+    };
+    let follow = follow || unsafe { has_trailing_slash(pathname) };
+    let target = match resolve_overlay_symlinks(&path_str, follow) {
+        Ok(target) => target,
+        Err(errno) => return Resolved::Error(errno),
+    };
+    if get_blacklist().lock().unwrap().is_blacklisted(&target) {
+        return unsafe { base_resolved(dirfd, pathname, &path_str, target) };
+    }
+    // Directories that exist in the base filesystem are left alone; one that only exists in an
+    // overlay, such as a directory created in the upper layer, is served from there.
+    let lookup = find_overlay_path(&target).or_else(|| {
+        if lstat_physical(&target).is_some() {
+            OverlayLookup::Base
         } else {
-            unsafe {
-                *__errno_location() = libc::ENOSYS;
-            }
-            return -1;
+            find_overlay_dir(&target)
         }
+    });
+    match lookup {
+        OverlayLookup::Found(overlay_path) => Resolved::Redirect(CString::new(overlay_path).unwrap()),
+        OverlayLookup::Hidden => Resolved::Error(libc::ENOENT),
+        OverlayLookup::Base => unsafe { base_resolved(dirfd, pathname, &path_str, target) },
     }
+}
+
+/// Whether `pathname` ends in a slash or `/.`, which makes the kernel resolve a final symlink even
+/// for calls that don't follow one otherwise.
+unsafe fn has_trailing_slash(pathname: *const c_char) -> bool {
+    unsafe { cstr_to_string(pathname) }.is_some_and(|path| {
+        let path = path.strip_suffix('.').filter(|p| p.ends_with('/')).unwrap_or(&path);
+        path.len() > 1 && path.ends_with('/')
+    })
+}
+
+/// Whether the kernel would look up the relative `pathname` for `dirfd` from inside an overlay,
+/// where `..` and sibling names lead out of the merged view.
+unsafe fn is_relative_to_overlay(dirfd: c_int, pathname: *const c_char) -> bool {
+    let relative = unsafe { cstr_to_string(pathname) }
+        .is_some_and(|path| !path.is_empty() && !path.starts_with('/'));
+    relative && is_dirfd_in_overlay(dirfd)
+}
 
-    let original_functions = get_original_functions();
+/// Whether the directory behind `dirfd`, or the working directory for `AT_FDCWD`, physically lies
+/// inside an overlay.
+fn is_dirfd_in_overlay(dirfd: c_int) -> bool {
     if dirfd == libc::AT_FDCWD {
-        // absolute path
-        if let Some(original_statx) = original_functions.statx {
-            let original_result = unsafe { original_statx(dirfd, pathname, flags, mask, statxbuf) };
-            if original_result == 0 {
-                if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-                    let mode = unsafe { (*statxbuf).stx_mode };
-                    if (mode & (libc::S_IFMT as u16)) == (libc::S_IFREG as u16) {
-                        if let Some(overlay_path) = find_overlay_path(&path_str) {
-                            let overlay_cstr = CString::new(overlay_path).unwrap();
-                            return unsafe { original_statx(dirfd, overlay_cstr.as_ptr(), flags, mask, statxbuf) };
-                        }
-                    } else if (mode & (libc::S_IFMT as u16)) == (libc::S_IFDIR as u16) {
-                        if let Some(overlay_path) = find_overlay_path(&path_str).or_else(|| find_overlay_dir(&path_str)) {
-                            let overlay_cstr = CString::new(overlay_path).unwrap();
-                            return unsafe { original_statx(dirfd, overlay_cstr.as_ptr(), flags, mask, statxbuf) };
-                        }
-                    }
-                }
-                return 0;
-            } else {
-                if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-                    if let Some(overlay_path) = find_overlay_path(&path_str).or_else(|| find_overlay_dir(&path_str)) {
-                        let overlay_cstr = CString::new(overlay_path).unwrap();
-                        return unsafe { original_statx(dirfd, overlay_cstr.as_ptr(), flags, mask, statxbuf) };
-                    }
-                }
-                return original_result;
-            }
-        } else {
-            unsafe {
-                *__errno_location() = libc::ENOSYS;
-            }
-            return -1;
+        is_cwd_in_overlay()
+    } else {
+        is_fd_in_layer(dirfd)
+    }
+}
+
+/// Whether the file behind `fd` physically lies inside one of the layers.
+fn is_fd_in_layer(fd: c_int) -> bool {
+    fd_physical_path(fd).is_some_and(|physical| split_overlay_path(&physical).is_some())
+}
+
+/// Sends a call for `path` to the base filesystem: with the caller's arguments, unless an overlay
+/// symlink along the way led to `target` or the kernel would resolve the caller's relative
+/// `pathname` inside an overlay, in which case it gets the absolute `target`.
+unsafe fn base_resolved(
+    dirfd: c_int,
+    pathname: *const c_char,
+    path: &str,
+    target: String,
+) -> Resolved {
+    if target == path && !unsafe { is_relative_to_overlay(dirfd, pathname) } {
+        Resolved::Original
+    } else {
+        Resolved::Redirect(CString::new(target).unwrap())
+    }
+}
+
+/// The absolute path to give the base filesystem in place of the caller's relative `pathname` when
+/// the kernel would resolve that inside an overlay, or `None` if the caller's path can go as it is.
+unsafe fn base_path_at(dirfd: c_int, pathname: *const c_char) -> Option<CString> {
+    if !unsafe { is_relative_to_overlay(dirfd, pathname) } {
+        return None;
+    }
+    unsafe { virtual_path_at(dirfd, pathname) }.and_then(|path| CString::new(path).ok())
+}
+
+/// Resolves `pathname` for `dirfd` for a call that modifies the file, following a final symlink
+/// if `follow`. With an upper layer configured, a file that lives in a lower overlay or the base
+/// filesystem is copied up first so the change lands in the upper layer and every other layer
+/// stays untouched.
+unsafe fn resolve_write_path_at(dirfd: c_int, pathname: *const c_char, follow: bool) -> Resolved {
+    let Some(path_str) = (unsafe { virtual_path_at(dirfd, pathname) }) else {
+        return Resolved::Original;
+    };
+    let follow = follow || unsafe { has_trailing_slash(pathname) };
+    let target = match resolve_overlay_symlinks(&path_str, follow) {
+        Ok(target) => target,
+        Err(errno) => return Resolved::Error(errno),
+    };
+    if is_read_only(&target) {
+        return Resolved::Error(read_only_errno(&target, false));
+    }
+    let Some(upper) = get_upper_dir() else {
+        return unsafe { resolve_lookup_at(dirfd, pathname, follow) };
+    };
+    if get_blacklist().lock().unwrap().is_blacklisted(&target) {
+        return unsafe { base_resolved(dirfd, pathname, &path_str, target) };
+    }
+    match copy_up(&upper, &target) {
+        Ok(upper_path) => Resolved::Redirect(CString::new(upper_path).unwrap()),
+        Err(libc::ENOENT) => unsafe { resolve_lookup_at(dirfd, pathname, follow) },
+        Err(errno) => Resolved::Error(errno),
+    }
+}
+
+unsafe fn resolve_write_path(pathname: *const c_char, follow: bool) -> Resolved {
+    unsafe { resolve_write_path_at(libc::AT_FDCWD, pathname, follow) }
+}
+
+/// Resolves `pathname` for `dirfd` for a call that creates a new entry. With an upper layer
+/// configured, a path missing from the merged view is created in the upper layer, with its parent
+/// directories copied up first, and a path that already exists fails with `EEXIST`.
+unsafe fn resolve_create_path_at(dirfd: c_int, pathname: *const c_char) -> Resolved {
+    let Some(path_str) = (unsafe { virtual_path_at(dirfd, pathname) }) else {
+        return Resolved::Original;
+    };
+    let target = match resolve_overlay_symlinks(&path_str, false) {
+        Ok(target) => target,
+        Err(errno) => return Resolved::Error(errno),
+    };
+    if is_read_only(&target) {
+        return Resolved::Error(read_only_errno(&target, true));
+    }
+    let Some(upper) = get_upper_dir() else {
+        return unsafe { resolve_link_path_at(dirfd, pathname) };
+    };
+    if get_blacklist().lock().unwrap().is_blacklisted(&target) {
+        return unsafe { base_resolved(dirfd, pathname, &path_str, target) };
+    }
+    match prepare_upper_create(&upper, &target) {
+        Ok(upper_path) => Resolved::Redirect(CString::new(upper_path).unwrap()),
+        Err(errno) => Resolved::Error(errno),
+    }
+}
+
+unsafe fn resolve_create_path(pathname: *const c_char) -> Resolved {
+    unsafe { resolve_create_path_at(libc::AT_FDCWD, pathname) }
+}
+
+fn is_write_open(flags: c_int) -> bool {
+    (flags & libc::O_ACCMODE) != libc::O_RDONLY || (flags & (libc::O_TRUNC | libc::O_APPEND)) != 0
+}
+
+/// Resolves the path of an `open`-style call, as a write if `flags` allow modifying the file.
+/// Device nodes in a layer can't be opened, as if the layers were mounted `nodev`, unless
+/// `OBSIDIANOS_OVERLAYS_ALLOW_DEVICES=1`.
+unsafe fn resolve_open_path_at(dirfd: c_int, pathname: *const c_char, flags: c_int) -> Resolved {
+    if (flags & libc::O_CREAT) != 0 {
+        match unsafe { resolve_create_path_at(dirfd, pathname) } {
+            Resolved::Error(libc::EEXIST) if (flags & libc::O_EXCL) == 0 => {}
+            resolved => return resolved,
         }
+    }
+    let follow = (flags & libc::O_NOFOLLOW) == 0;
+    let resolved = if is_write_open(flags) && !unsafe { is_special_path_at(dirfd, pathname, follow) } {
+        unsafe { resolve_write_path_at(dirfd, pathname, follow) }
     } else {
-        // relative path
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if let Some(overlay_path) = find_overlay_path(&path_str) {
-                let overlay_cstr = CString::new(overlay_path).unwrap();
-                if let Some(original_statx) = original_functions.statx {
-                    return unsafe {
-                        original_statx(dirfd, overlay_cstr.as_ptr(), flags, mask, statxbuf)
-                    };
-                } else {
-                    unsafe {
-                        *__errno_location() = libc::ENOSYS;
-                    }
-                    return -1;
+        unsafe { resolve_lookup_at(dirfd, pathname, follow) }
+    };
+    if let Resolved::Redirect(target_cstr) = &resolved
+        && !is_allow_devices_enabled()
+        && let Ok(target) = target_cstr.to_str()
+        && split_overlay_path(target).is_some()
+        && lstat_physical(target).is_some_and(|st| is_layer_device(&st))
+    {
+        return Resolved::Error(libc::EACCES);
+    }
+    resolved
+}
+
+/// Whether `st` is a character or block device that a layer ships, rather than a whiteout.
+fn is_layer_device(st: &libc::stat) -> bool {
+    matches!(st.st_mode & libc::S_IFMT, libc::S_IFBLK | libc::S_IFCHR) && !is_whiteout_device(st)
+}
+
+/// Whether `pathname` for `dirfd` is a FIFO, socket or device node in the merged view. Writing to
+/// one doesn't change the filesystem, so it is opened where it is instead of being copied up.
+unsafe fn is_special_path_at(dirfd: c_int, pathname: *const c_char, follow: bool) -> bool {
+    let Some(path_str) = (unsafe { virtual_path_at(dirfd, pathname) }) else {
+        return false;
+    };
+    resolve_overlay_symlinks(&path_str, follow)
+        .and_then(|target| visible_source(&target))
+        .ok()
+        .and_then(|source| lstat_physical(&source))
+        .is_some_and(|st| is_special_file(&st))
+}
+
+/// Translates an `fopen` mode string into the equivalent `open` flags.
+unsafe fn fopen_flags(mode: *const c_char) -> c_int {
+    let mode = unsafe { cstr_to_string(mode) }.unwrap_or_default();
+    let plus = mode.contains('+');
+    match mode.chars().next() {
+        Some('w') => (if plus { libc::O_RDWR } else { libc::O_WRONLY }) | libc::O_CREAT | libc::O_TRUNC,
+        Some('a') => (if plus { libc::O_RDWR } else { libc::O_WRONLY }) | libc::O_CREAT | libc::O_APPEND,
+        _ if plus => libc::O_RDWR,
+        _ => libc::O_RDONLY,
+    }
+}
+
+fn set_errno(errno: c_int) {
+    unsafe {
+        *__errno_location() = errno;
+    }
+}
+
+fn fail(errno: c_int) -> c_int {
+    set_errno(errno);
+    -1
+}
+
+/// `lstat` on a physical path, bypassing the overlays.
+fn lstat_physical(path: &str) -> Option<libc::stat> {
+    let path_cstr = CString::new(path).ok()?;
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { (get_original_functions().lstat)(path_cstr.as_ptr(), &mut st) } == 0 {
+        Some(st)
+    } else {
+        None
+    }
+}
+
+fn is_whiteout_device(st: &libc::stat) -> bool {
+    (st.st_mode & libc::S_IFMT) == libc::S_IFCHR && st.st_rdev == 0
+}
+
+/// Whether an overlay entry replaces the path it overlays outright: any type but a directory,
+/// which merges instead, or a whiteout.
+fn is_overlay_file(st: &libc::stat) -> bool {
+    (st.st_mode & libc::S_IFMT) != libc::S_IFDIR && !is_whiteout_device(st)
+}
+
+/// Whether `st` describes a FIFO, socket or device node.
+fn is_special_file(st: &libc::stat) -> bool {
+    matches!(
+        st.st_mode & libc::S_IFMT,
+        libc::S_IFIFO | libc::S_IFSOCK | libc::S_IFCHR | libc::S_IFBLK
+    )
+}
+
+/// Whether `overlay` hides `path` from the layers below it: with a whiteout for the path or one
+/// of the directories above it (an overlayfs-style 0/0 character device in place of the entry, or
+/// a `.wh.<name>` file beside it), or because one of those directories is opaque.
+fn hides_lower_layers(overlay: &str, path: &str) -> bool {
+    let mut parent = overlay.trim_end_matches('/').to_string();
+    let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+    while let Some(component) = components.next() {
+        let entry = format!("{}/{}", parent, component);
+        match lstat_physical(&entry) {
+            Some(st) if is_whiteout_device(&st) => return true,
+            Some(st) if (st.st_mode & libc::S_IFMT) == libc::S_IFDIR => {
+                if components.peek().is_some() && is_opaque_dir(&entry) {
+                    return true;
                 }
             }
+            // Nothing deeper can exist in this overlay, so only a marker at this level matters.
+            _ => return lstat_physical(&format!("{}/.wh.{}", parent, component)).is_some(),
         }
-        if let Some(original_statx) = original_functions.statx {
-            unsafe { original_statx(dirfd, pathname, flags, mask, statxbuf) }
-        } else {
-            unsafe {
-                *__errno_location() = libc::ENOSYS;
-            }
-            -1
-        }
+        parent = entry;
     }
+    false
 }
 
-fn find_overlay_path(path: &str) -> Option<String> {
-    if path.starts_with("/etc/obsidianos-overlays.conf") {
-        return None;
+/// Marker file that makes the overlay directory containing it opaque.
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+/// Whether the overlay directory `dir` is opaque, i.e. replaces rather than merges with the same
+/// directory in the layers below. Set either with the overlayfs `trusted.overlay.opaque` xattr or
+/// with an `.wh..wh..opq` marker file inside it.
+fn is_opaque_dir(dir: &str) -> bool {
+    let Ok(dir_cstr) = CString::new(dir) else {
+        return false;
+    };
+    let mut value = [0u8; 1];
+    let len = unsafe {
+        (get_original_functions().lgetxattr)(
+            dir_cstr.as_ptr(),
+            c"trusted.overlay.opaque".as_ptr(),
+            value.as_mut_ptr() as *mut libc::c_void,
+            value.len(),
+        )
+    };
+    if len == 1 && value[0] == b'y' {
+        return true;
+    }
+    lstat_physical(&format!("{}/{}", dir, OPAQUE_MARKER)).is_some()
+}
+
+/// Whether an overlay hides `path`, whatever type of entry it would otherwise be.
+fn is_hidden(path: &str) -> bool {
+    matches!(
+        find_overlay_path(path).or_else(|| find_overlay_dir(path)),
+        OverlayLookup::Hidden
+    )
+}
+
+fn find_overlay_path(path: &str) -> OverlayLookup {
+    if path.starts_with("/etc/obsidianos-overlays.") {
+        return OverlayLookup::Base;
     }
 
     let overlays = get_overlay_config();
     for overlay in overlays {
         let overlay_path = format!("{}{}", overlay, path);
-        let is_file = OVERLAY_DISABLED.with(|disabled| {
-            *disabled.borrow_mut() = true;
-            let is_file = Path::new(&overlay_path).is_file();
-            *disabled.borrow_mut() = false;
-            is_file
-        });
+        // A symlink is an entry of its own, whether or not its target exists.
+        let is_file = lstat_physical(&overlay_path).is_some_and(|st| is_overlay_file(&st));
 
         if is_verbose_mode_enabled() {
             eprintln!(
@@ -480,26 +1108,28 @@ fn find_overlay_path(path: &str) -> Option<String> {
             if is_verbose_mode_enabled() {
                 eprintln!("[*] ObsidianOS Overlays: {} -> {}", path, overlay_path);
             }
-            return Some(overlay_path);
+            return OverlayLookup::Found(overlay_path);
+        }
+        if hides_lower_layers(&overlay, path) {
+            if is_verbose_mode_enabled() {
+                eprintln!("[*] ObsidianOS Overlays: {} hidden by {}", path, overlay);
+            }
+            return OverlayLookup::Hidden;
         }
     }
-    None
+    OverlayLookup::Base
 }
 
-fn find_overlay_dir(path: &str) -> Option<String> {
-    if path.starts_with("/etc/obsidianos-overlays.conf") {
-        return None;
+fn find_overlay_dir(path: &str) -> OverlayLookup {
+    if path.starts_with("/etc/obsidianos-overlays.") {
+        return OverlayLookup::Base;
     }
 
     let overlays = get_overlay_config();
     for overlay in overlays {
         let overlay_path = format!("{}{}", overlay, path);
-        let is_dir = OVERLAY_DISABLED.with(|disabled| {
-            *disabled.borrow_mut() = true;
-            let is_dir = Path::new(&overlay_path).is_dir();
-            *disabled.borrow_mut() = false;
-            is_dir
-        });
+        let is_dir = lstat_physical(&overlay_path)
+            .is_some_and(|st| (st.st_mode & libc::S_IFMT) == libc::S_IFDIR);
 
         if is_verbose_mode_enabled() {
             eprintln!(
@@ -511,1096 +1141,3449 @@ fn find_overlay_dir(path: &str) -> Option<String> {
             if is_verbose_mode_enabled() {
                 eprintln!("[*] ObsidianOS Overlays: dir {} -> {}", path, overlay_path);
             }
-            return Some(overlay_path);
+            return OverlayLookup::Found(overlay_path);
+        }
+        if hides_lower_layers(&overlay, path) {
+            if is_verbose_mode_enabled() {
+                eprintln!("[*] ObsidianOS Overlays: {} hidden by {}", path, overlay);
+            }
+            return OverlayLookup::Hidden;
         }
     }
-    None
+    OverlayLookup::Base
 }
 
-unsafe fn cstr_to_string(ptr: *const c_char) -> Option<String> {
-    if ptr.is_null() {
-        return None;
+/// Looks up the topmost layer holding an entry of any type at `path`, without following a final
+/// symlink.
+fn find_overlay_entry(path: &str) -> OverlayLookup {
+    if path.starts_with("/etc/obsidianos-overlays.") {
+        return OverlayLookup::Base;
     }
-
-    match unsafe { CStr::from_ptr(ptr).to_str() } {
-        Ok(s) => Some(s.to_string()),
-        Err(_) => None,
+    for overlay in get_overlay_config() {
+        let overlay_path = format!("{}{}", overlay, path);
+        match lstat_physical(&overlay_path) {
+            Some(st) if !is_whiteout_device(&st) => return OverlayLookup::Found(overlay_path),
+            _ => {}
+        }
+        if hides_lower_layers(&overlay, path) {
+            return OverlayLookup::Hidden;
+        }
     }
+    OverlayLookup::Base
 }
 
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn open(pathname: *const c_char, flags: c_int, mode: mode_t) -> c_int {
-    if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().open)(pathname, flags, mode) };
+/// Returns the physical path currently serving `path` in the merged view, if it exists.
+fn visible_source(path: &str) -> Result<String, c_int> {
+    match find_overlay_entry(path) {
+        OverlayLookup::Found(overlay_path) => Ok(overlay_path),
+        OverlayLookup::Hidden => Err(libc::ENOENT),
+        OverlayLookup::Base if lstat_physical(path).is_some() => Ok(path.to_string()),
+        OverlayLookup::Base => Err(libc::ENOENT),
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().open)(pathname, flags, mode) };
+}
+
+/// Returns the visible symlink that an overlay holds at the shallowest prefix of `path`, as the
+/// length of that prefix and the link's target. The final component only counts if `follow`.
+/// Base filesystem symlinks are left to the kernel.
+fn find_overlay_symlink(path: &str, follow: bool) -> Option<(usize, String)> {
+    // Overlays that still have a directory at the prefix walked so far; anything deeper can only
+    // exist in those.
+    let mut live = get_overlay_config();
+    let mut end = 0;
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        end = path[end..].find(component)? + end + component.len();
+        if end == path.len() && !follow {
+            break;
         }
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe { (get_original_functions().open)(overlay_cstr.as_ptr(), flags, mode) };
+        let prefix = &path[..end];
+        let mut has_symlink = false;
+        live.retain(|overlay| match lstat_physical(&format!("{}{}", overlay, prefix)) {
+            Some(st) if (st.st_mode & libc::S_IFMT) == libc::S_IFLNK => {
+                has_symlink = true;
+                false
+            }
+            Some(st) => (st.st_mode & libc::S_IFMT) == libc::S_IFDIR,
+            None => false,
+        });
+        if has_symlink
+            && let OverlayLookup::Found(overlay_path) = find_overlay_entry(prefix)
+            && let Some(target) = read_symlink(&overlay_path)
+        {
+            return Some((end, target));
+        }
+        if live.is_empty() {
+            break;
         }
     }
-    unsafe { (get_original_functions().open)(pathname, flags, mode) }
+    None
+}
+
+/// Returns the visible symlink at the shallowest prefix of `path` like `find_overlay_symlink`, but
+/// in whichever layer it lives, the base filesystem included.
+fn find_merged_symlink(path: &str, follow: bool) -> Option<(usize, String)> {
+    let mut end = 0;
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        end = path[end..].find(component)? + end + component.len();
+        if end == path.len() && !follow {
+            break;
+        }
+        let prefix = &path[..end];
+        let source = if get_blacklist().lock().unwrap().is_blacklisted(prefix) {
+            prefix.to_string()
+        } else {
+            visible_source(prefix).ok()?
+        };
+        let st = lstat_physical(&source)?;
+        match st.st_mode & libc::S_IFMT {
+            libc::S_IFLNK => return read_symlink(&source).map(|target| (end, target)),
+            libc::S_IFDIR => {}
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Expands the symlinks that overlays hold along `path`, resolving each target in the merged view
+/// rather than on the filesystem the link happens to live on, so a link shipped in an overlay can
+/// point at files from any layer. The final component is only expanded if `follow`. Fails with
+/// `ELOOP` after `MAX_SYMLINK_DEPTH` expansions. Blacklisted paths are returned unchanged.
+fn resolve_overlay_symlinks(path: &str, follow: bool) -> Result<String, c_int> {
+    let mut path = path.to_string();
+    let mut expanded = 0;
+    while !get_blacklist().lock().unwrap().is_blacklisted(&path) {
+        let Some((end, target)) = find_overlay_symlink(&path, follow) else {
+            break;
+        };
+        if expanded == MAX_SYMLINK_DEPTH {
+            return Err(libc::ELOOP);
+        }
+        expanded += 1;
+        let link_dir = if target.starts_with('/') { "" } else { parent_path(&path[..end]) };
+        let expanded_path = normalize_path(&format!("{}/{}{}", link_dir, target, &path[end..]));
+        if is_verbose_mode_enabled() {
+            eprintln!("[DEBUG] resolve_overlay_symlinks: {} -> {}", path, expanded_path);
+        }
+        path = expanded_path;
+    }
+    Ok(path)
+}
+
+fn last_errno() -> c_int {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(libc::EIO)
+}
+
+/// Returns the directory containing the absolute path `path`.
+fn parent_path(path: &str) -> &str {
+    match path.rsplit_once('/') {
+        Some(("", _)) | None => "/",
+        Some((parent, _)) => parent,
+    }
+}
+
+/// Copies `path` into `layer` unless it is already there, creating its parent directories in
+/// `layer` as needed, and returns its physical path in `layer`.
+fn copy_up(layer: &str, path: &str) -> Result<String, c_int> {
+    let layer_path = format!("{}{}", layer, path);
+    let source = visible_source(path)?;
+    if source == layer_path {
+        return Ok(layer_path);
+    }
+    ensure_layer_dir(layer, parent_path(path))?;
+    if is_verbose_mode_enabled() {
+        eprintln!("[*] ObsidianOS Overlays: copy-up {} -> {}", source, layer_path);
+    }
+    copy_entry(&source, &layer_path)?;
+    Ok(layer_path)
+}
+
+/// Prepares the upper layer for creating `path`, which must not exist in the merged view, and
+/// returns the physical path to create. A whiteout the upper layer holds for `path` is removed,
+/// since the new entry replaces it.
+fn prepare_upper_create(upper: &str, path: &str) -> Result<String, c_int> {
+    if visible_source(path).is_ok() {
+        return Err(libc::EEXIST);
+    }
+    ensure_layer_dir(upper, parent_path(path))?;
+    clear_whiteout(upper, path)?;
+    Ok(format!("{}{}", upper, path))
+}
+
+/// Makes sure the directory `path` exists in `layer`, copying up its parents first so each one
+/// keeps the mode, ownership and timestamps it has in the merged view.
+fn ensure_layer_dir(layer: &str, path: &str) -> Result<(), c_int> {
+    let layer_path = format!("{}{}", layer, path);
+    if let Some(st) = lstat_physical(&layer_path) {
+        return if (st.st_mode & libc::S_IFMT) == libc::S_IFDIR {
+            Ok(())
+        } else {
+            Err(libc::ENOTDIR)
+        };
+    }
+    if path == "/" {
+        return Err(libc::ENOENT);
+    }
+    ensure_layer_dir(layer, parent_path(path))?;
+    copy_entry(&visible_source(path)?, &layer_path)
+}
+
+/// Returns the overlay whose entry at `path` is visible in the merged view, or `None` if the base
+/// filesystem's is.
+fn entry_layer(path: &str) -> Result<Option<String>, c_int> {
+    let source = visible_source(path)?;
+    Ok(get_overlay_config()
+        .into_iter()
+        .find(|overlay| source == format!("{}{}", overlay, path)))
+}
+
+/// The layer that removals and renames of `path` are carried out in: the upper layer if one is
+/// configured, otherwise the overlay currently serving `path`. `None` means the base filesystem is
+/// modified directly.
+fn write_layer(path: &str) -> Result<Option<String>, c_int> {
+    match get_upper_dir() {
+        Some(upper) => Ok(Some(upper)),
+        None => entry_layer(path),
+    }
+}
+
+/// Whether a layer below the topmost overlay also has a directory at `path`, which would merge
+/// into it.
+fn has_lower_dirs(path: &str) -> bool {
+    let (overlay_dirs, base_visible) = find_overlay_dirs(path);
+    overlay_dirs.len() > 1
+        || (base_visible
+            && lstat_physical(path).is_some_and(|st| (st.st_mode & libc::S_IFMT) == libc::S_IFDIR))
+}
+
+/// Whether the merged directory `path` has no entries besides `.` and `..`.
+fn is_merged_dir_empty(path: &str) -> bool {
+    unsafe { read_merged_entries(path) }
+        .iter()
+        .all(|entry| entry.name == b"." || entry.name == b"..")
+}
+
+/// Returns the physical path of the `.wh.<name>` file that whites out `path` in `layer`.
+fn whiteout_marker(layer: &str, path: &str) -> String {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    format!("{}{}/.wh.{}", layer, parent_path(path).trim_end_matches('/'), name)
+}
+
+/// Records in `layer` that `path` has been deleted, hiding it in every layer below.
+fn write_whiteout(layer: &str, path: &str) -> Result<(), c_int> {
+    ensure_layer_dir(layer, parent_path(path))?;
+    let marker = whiteout_marker(layer, path);
+    if is_verbose_mode_enabled() {
+        eprintln!("[*] ObsidianOS Overlays: whiteout {}", marker);
+    }
+    create_marker_file(&marker)
+}
+
+/// Removes the whiteout `layer` holds for `path`, if any.
+fn clear_whiteout(layer: &str, path: &str) -> Result<(), c_int> {
+    let marker = whiteout_marker(layer, path);
+    let layer_path = format!("{}{}", layer, path);
+    let device = Some(layer_path).filter(|p| lstat_physical(p).is_some_and(|st| is_whiteout_device(&st)));
+    let marker = Some(marker).filter(|m| lstat_physical(m).is_some());
+    for whiteout in marker.into_iter().chain(device) {
+        let whiteout_cstr = CString::new(whiteout).map_err(|_| libc::EINVAL)?;
+        if unsafe { (get_original_functions().unlink)(whiteout_cstr.as_ptr()) } != 0 {
+            return Err(last_errno());
+        }
+    }
+    Ok(())
+}
+
+/// Makes the directory `path`, which was just created in the topmost layer, opaque if a lower layer
+/// has a directory there too, so that it starts out empty.
+fn hide_lower_dirs(path: &str) {
+    if !has_lower_dirs(path) {
+        return;
+    }
+    if let Some(top_dir) = find_overlay_dirs(path).0.first() {
+        let _ = create_marker_file(&format!("{}/{}", top_dir, OPAQUE_MARKER));
+    }
+}
+
+fn create_marker_file(path: &str) -> Result<(), c_int> {
+    let path_cstr = CString::new(path).map_err(|_| libc::EINVAL)?;
+    let fd = unsafe {
+        (get_original_functions().open)(
+            path_cstr.as_ptr(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC,
+            0o644,
+        )
+    };
+    if fd < 0 {
+        return Err(last_errno());
+    }
+    unsafe { (get_original_functions().close)(fd) };
+    Ok(())
+}
+
+/// Deletes the whiteouts and opaque marker inside the overlay directory `dir`, which leaves it
+/// physically empty when the merged directory is empty.
+fn purge_whiteouts(dir: &str) -> Result<(), c_int> {
+    let orig = get_original_functions();
+    let dir_cstr = CString::new(dir).map_err(|_| libc::EINVAL)?;
+    let dir_ptr = unsafe { (orig.opendir)(dir_cstr.as_ptr()) };
+    if dir_ptr.is_null() {
+        return Err(last_errno());
+    }
+    let mut markers = Vec::new();
+    loop {
+        let dirent64_ptr = unsafe { (orig.readdir64)(dir_ptr) };
+        if dirent64_ptr.is_null() {
+            break;
+        }
+        let dirent64 = unsafe { &*dirent64_ptr };
+        let name = unsafe { CStr::from_ptr(dirent64.d_name.as_ptr()) }.to_bytes().to_vec();
+        if name == OPAQUE_MARKER.as_bytes() || whiteout_target(dir, &name, dirent64.d_type).is_some() {
+            markers.push(name);
+        }
+    }
+    unsafe { (orig.closedir)(dir_ptr) };
+    for name in markers {
+        let marker = format!("{}/{}", dir, String::from_utf8_lossy(&name));
+        let marker_cstr = CString::new(marker).map_err(|_| libc::EINVAL)?;
+        if unsafe { (orig.unlink)(marker_cstr.as_ptr()) } != 0 {
+            return Err(last_errno());
+        }
+    }
+    Ok(())
+}
+
+/// Deletes `path` from the merged view through `layer`: its own copy in `layer` is removed, and a
+/// whiteout is left in `layer` if a lower layer would otherwise show through. `dir` selects
+/// `rmdir` semantics, which only remove a directory whose merged view is empty.
+fn remove_entry(layer: &str, path: &str, dir: bool) -> Result<(), c_int> {
+    let source = visible_source(path)?;
+    let st = lstat_physical(&source).ok_or(libc::ENOENT)?;
+    let is_dir = (st.st_mode & libc::S_IFMT) == libc::S_IFDIR;
+    if dir && !is_dir {
+        return Err(libc::ENOTDIR);
+    }
+    if !dir && is_dir {
+        return Err(libc::EISDIR);
+    }
+    if is_dir && !is_merged_dir_empty(path) {
+        return Err(libc::ENOTEMPTY);
+    }
+    let layer_path = format!("{}{}", layer, path);
+    if source == layer_path {
+        let layer_cstr = CString::new(layer_path.as_str()).map_err(|_| libc::EINVAL)?;
+        let result = if is_dir {
+            purge_whiteouts(&layer_path)?;
+            unsafe { (get_original_functions().rmdir)(layer_cstr.as_ptr()) }
+        } else {
+            unsafe { (get_original_functions().unlink)(layer_cstr.as_ptr()) }
+        };
+        if result != 0 {
+            return Err(last_errno());
+        }
+    }
+    if visible_source(path).is_ok() {
+        write_whiteout(layer, path)?;
+    }
+    Ok(())
+}
+
+/// Renames `old` to `new` in the merged view through `layer`. Files are copied up into `layer`
+/// first. A directory can only be renamed if it lives entirely in `layer`; otherwise this fails with
+/// `EXDEV`, as overlayfs does, and callers such as `mv` fall back to copying. A whiteout is left for
+/// `old` if a lower layer would otherwise show through.
+fn rename_entry(layer: &str, old: &str, new: &str) -> Result<(), c_int> {
+    let source = visible_source(old)?;
+    let st = lstat_physical(&source).ok_or(libc::ENOENT)?;
+    let is_dir = (st.st_mode & libc::S_IFMT) == libc::S_IFDIR;
+    if old == new {
+        return Ok(());
+    }
+    if is_dir && new.starts_with(&format!("{}/", old)) {
+        return Err(libc::EINVAL);
+    }
+    let layer_new = format!("{}{}", layer, new);
+    if let Ok(target) = visible_source(new) {
+        let target_st = lstat_physical(&target).ok_or(libc::ENOENT)?;
+        let target_is_dir = (target_st.st_mode & libc::S_IFMT) == libc::S_IFDIR;
+        if is_dir && !target_is_dir {
+            return Err(libc::ENOTDIR);
+        }
+        if !is_dir && target_is_dir {
+            return Err(libc::EISDIR);
+        }
+        if target_is_dir {
+            if !is_merged_dir_empty(new) {
+                return Err(libc::ENOTEMPTY);
+            }
+            if target == layer_new {
+                purge_whiteouts(&layer_new)?;
+            }
+        }
+    }
+    let layer_old = format!("{}{}", layer, old);
+    if is_dir && (source != layer_old || has_lower_dirs(old)) {
+        return Err(libc::EXDEV);
+    }
+    let layer_old = copy_up(layer, old)?;
+    ensure_layer_dir(layer, parent_path(new))?;
+    clear_whiteout(layer, new)?;
+    let old_cstr = CString::new(layer_old).map_err(|_| libc::EINVAL)?;
+    let new_cstr = CString::new(layer_new).map_err(|_| libc::EINVAL)?;
+    if unsafe { (get_original_functions().rename)(old_cstr.as_ptr(), new_cstr.as_ptr()) } != 0 {
+        return Err(last_errno());
+    }
+    if is_dir {
+        hide_lower_dirs(new);
+    }
+    if visible_source(old).is_ok() {
+        write_whiteout(layer, old)?;
+    }
+    Ok(())
+}
+
+/// Renames `old` to `new` in `layer` like `rename_entry`, under the `renameat2` `flags` that
+/// `check_rename_flags` accepted. `RENAME_WHITEOUT` always leaves a whiteout at `old`.
+fn rename_entry_with_flags(layer: &str, old: &str, new: &str, flags: c_uint) -> Result<(), c_int> {
+    if (flags & libc::RENAME_EXCHANGE) != 0 {
+        return exchange_entries(layer, old, new);
+    }
+    rename_entry(layer, old, new)?;
+    if (flags & libc::RENAME_WHITEOUT) != 0 && old != new && !is_hidden(old) {
+        write_whiteout(layer, old)?;
+    }
+    Ok(())
+}
+
+/// Atomically swaps `old` and `new` by copying both up into `layer` and exchanging them there.
+/// Like a rename, a directory can only take part if it lives entirely in `layer`.
+fn exchange_entries(layer: &str, old: &str, new: &str) -> Result<(), c_int> {
+    let mut layer_paths = Vec::new();
+    for path in [old, new] {
+        let source = visible_source(path)?;
+        let st = lstat_physical(&source).ok_or(libc::ENOENT)?;
+        let is_dir = (st.st_mode & libc::S_IFMT) == libc::S_IFDIR;
+        if is_dir && (source != format!("{}{}", layer, path) || has_lower_dirs(path)) {
+            return Err(libc::EXDEV);
+        }
+        layer_paths.push(source);
+    }
+    if old == new {
+        return Ok(());
+    }
+    if new.starts_with(&format!("{}/", old)) || old.starts_with(&format!("{}/", new)) {
+        return Err(libc::EINVAL);
+    }
+    let old_cstr = CString::new(copy_up(layer, old)?).map_err(|_| libc::EINVAL)?;
+    let new_cstr = CString::new(copy_up(layer, new)?).map_err(|_| libc::EINVAL)?;
+    if is_verbose_mode_enabled() {
+        eprintln!("[*] ObsidianOS Overlays: exchange {} <-> {}", layer_paths[0], layer_paths[1]);
+    }
+    let result = unsafe {
+        (get_original_functions().renameat2)(
+            libc::AT_FDCWD,
+            old_cstr.as_ptr(),
+            libc::AT_FDCWD,
+            new_cstr.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if result != 0 {
+        return Err(last_errno());
+    }
+    Ok(())
+}
+
+/// Copies one entry, non-recursively, preserving its type, content, mode, ownership, extended
+/// attributes and timestamps. Regular files are written to a temporary name and renamed into place
+/// so the destination never holds a partial copy.
+fn copy_entry(source: &str, dest: &str) -> Result<(), c_int> {
+    let st = lstat_physical(source).ok_or(libc::ENOENT)?;
+    let source_cstr = CString::new(source).map_err(|_| libc::EINVAL)?;
+    let dest_cstr = CString::new(dest).map_err(|_| libc::EINVAL)?;
+    let orig = get_original_functions();
+    let perm = st.st_mode & 0o7777;
+    match st.st_mode & libc::S_IFMT {
+        libc::S_IFREG => {
+            let tmp_cstr = CString::new(format!(
+                "{}.obsidianos-copy-up.{}.{}.{}",
+                dest,
+                std::process::id(),
+                unsafe { libc::gettid() },
+                COPY_UP_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ))
+            .map_err(|_| libc::EINVAL)?;
+            let src_fd = unsafe { (orig.open)(source_cstr.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC, 0) };
+            if src_fd < 0 {
+                return Err(last_errno());
+            }
+            let dest_fd = unsafe {
+                (orig.open)(
+                    tmp_cstr.as_ptr(),
+                    libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_CLOEXEC,
+                    0o600,
+                )
+            };
+            if dest_fd < 0 {
+                let errno = last_errno();
+                unsafe { (orig.close)(src_fd) };
+                return Err(errno);
+            }
+            let mut result = copy_file_data(src_fd, dest_fd);
+            if result.is_ok() {
+                copy_fd_metadata(src_fd, dest_fd, &st, dest);
+                result = (unsafe { (orig.rename)(tmp_cstr.as_ptr(), dest_cstr.as_ptr()) } == 0)
+                    .then_some(())
+                    .ok_or_else(last_errno);
+            }
+            unsafe {
+                (orig.close)(src_fd);
+                (orig.close)(dest_fd);
+            }
+            if result.is_err() {
+                unsafe { (orig.unlink)(tmp_cstr.as_ptr()) };
+            }
+            result
+        }
+        libc::S_IFDIR => {
+            if unsafe { (orig.mkdir)(dest_cstr.as_ptr(), 0o700) } != 0 {
+                return Err(last_errno());
+            }
+            let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+            let src_fd = unsafe { (orig.open)(source_cstr.as_ptr(), flags, 0) };
+            let dest_fd = unsafe { (orig.open)(dest_cstr.as_ptr(), flags, 0) };
+            if src_fd >= 0 && dest_fd >= 0 {
+                copy_fd_metadata(src_fd, dest_fd, &st, dest);
+            } else {
+                unsafe { (orig.chmod)(dest_cstr.as_ptr(), perm) };
+            }
+            unsafe {
+                (orig.close)(src_fd);
+                (orig.close)(dest_fd);
+            }
+            Ok(())
+        }
+        libc::S_IFLNK => {
+            let target = read_symlink(source).ok_or(libc::EIO)?;
+            let target_cstr = CString::new(target).map_err(|_| libc::EINVAL)?;
+            if unsafe { (orig.symlink)(target_cstr.as_ptr(), dest_cstr.as_ptr()) } != 0 {
+                return Err(last_errno());
+            }
+            unsafe {
+                (orig.lchown)(dest_cstr.as_ptr(), st.st_uid, st.st_gid);
+                (orig.utimensat)(
+                    libc::AT_FDCWD,
+                    dest_cstr.as_ptr(),
+                    stat_times(&st).as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                );
+            }
+            Ok(())
+        }
+        _ => {
+            if unsafe { (orig.mknod)(dest_cstr.as_ptr(), st.st_mode, st.st_rdev) } != 0 {
+                return Err(last_errno());
+            }
+            unsafe {
+                (orig.lchown)(dest_cstr.as_ptr(), st.st_uid, st.st_gid);
+                (orig.utimensat)(libc::AT_FDCWD, dest_cstr.as_ptr(), stat_times(&st).as_ptr(), 0);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn copy_file_data(src_fd: c_int, dest_fd: c_int) -> Result<(), c_int> {
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = unsafe { libc::read(src_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if read < 0 {
+            if last_errno() == libc::EINTR {
+                continue;
+            }
+            return Err(last_errno());
+        }
+        if read == 0 {
+            return Ok(());
+        }
+        let mut written = 0;
+        while written < read as usize {
+            let n = unsafe {
+                libc::write(
+                    dest_fd,
+                    buf[written..].as_ptr() as *const libc::c_void,
+                    read as usize - written,
+                )
+            };
+            if n < 0 {
+                if last_errno() == libc::EINTR {
+                    continue;
+                }
+                return Err(last_errno());
+            }
+            written += n as usize;
+        }
+    }
+}
+
+fn stat_times(st: &libc::stat) -> [libc::timespec; 2] {
+    [
+        libc::timespec {
+            tv_sec: st.st_atime,
+            tv_nsec: st.st_atime_nsec,
+        },
+        libc::timespec {
+            tv_sec: st.st_mtime,
+            tv_nsec: st.st_mtime_nsec,
+        },
+    ]
+}
+
+/// Reports a copy-up step that failed for `dest`. Losing ownership or an attribute the process
+/// may not set is expected without privileges and only shown in verbose mode.
+fn log_copy_up_failure(dest: &str, step: &str, errno: c_int) {
+    let message = format!(
+        "Copy-up of {}: {} failed: {}",
+        dest,
+        step,
+        std::io::Error::from_raw_os_error(errno)
+    );
+    if matches!(errno, libc::EPERM | libc::EOPNOTSUPP | libc::ENODATA) {
+        if is_verbose_mode_enabled() {
+            eprintln!("[DEBUG] {}", message);
+        }
+    } else {
+        eprintln!("[ERROR] {}", message);
+    }
+}
+
+/// Copies ownership, extended attributes, mode and timestamps from `src_fd` to `dest_fd`, the
+/// copy-up of `dest`, through the original xattr calls so nothing is filtered on the way. Each
+/// step is best effort: an unprivileged copy-up cannot keep foreign ownership, and that must not
+/// stop the write it was made for.
+fn copy_fd_metadata(src_fd: c_int, dest_fd: c_int, st: &libc::stat, dest: &str) {
+    let orig = get_original_functions();
+    if unsafe { libc::fchown(dest_fd, st.st_uid, st.st_gid) } != 0 {
+        log_copy_up_failure(dest, "fchown", last_errno());
+    }
+    let names = read_xattr_buffer(|buf, len| unsafe {
+        (orig.flistxattr)(src_fd, buf as *mut c_char, len)
+    })
+    .unwrap_or_else(|errno| {
+        log_copy_up_failure(dest, "flistxattr", errno);
+        Vec::new()
+    });
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        if name.starts_with(PRIVATE_XATTR_PREFIX) {
+            continue;
+        }
+        let Ok(name_cstr) = CString::new(name) else {
+            continue;
+        };
+        let step = format!("copying {}", name_cstr.to_string_lossy());
+        let value = match read_xattr_buffer(|buf, len| unsafe {
+            (orig.fgetxattr)(src_fd, name_cstr.as_ptr(), buf as *mut libc::c_void, len)
+        }) {
+            Ok(value) => value,
+            Err(errno) => {
+                log_copy_up_failure(dest, &step, errno);
+                continue;
+            }
+        };
+        let result = unsafe {
+            (orig.fsetxattr)(
+                dest_fd,
+                name_cstr.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if result != 0 {
+            log_copy_up_failure(dest, &step, last_errno());
+        }
+    }
+    // After fchown, which clears the set-id bits.
+    if unsafe { libc::fchmod(dest_fd, st.st_mode & 0o7777) } != 0 {
+        log_copy_up_failure(dest, "fchmod", last_errno());
+    }
+    if unsafe { libc::futimens(dest_fd, stat_times(st).as_ptr()) } != 0 {
+        log_copy_up_failure(dest, "futimens", last_errno());
+    }
+}
+
+unsafe fn cstr_to_string(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+
+    match unsafe { CStr::from_ptr(ptr).to_str() } {
+        Ok(s) => Some(s.to_string()),
+        Err(_) => None,
+    }
+}
+
+/// Returns the virtual working directory: the kernel's, mapped out of the overlay it lies in when
+/// it was entered through the merged view, asking the kernel only after it may have changed.
+fn current_dir() -> Option<String> {
+    load_current_dir().map(|(path, _)| path)
+}
+
+/// Whether the kernel's working directory is the physical directory of an overlay, from which it
+/// would resolve relative paths outside the merged view.
+fn is_cwd_in_overlay() -> bool {
+    load_current_dir().is_some_and(|(_, in_overlay)| in_overlay)
+}
+
+fn load_current_dir() -> Option<(String, bool)> {
+    let mut cwd = CURRENT_DIR.get_or_init(|| Mutex::new(None)).lock().unwrap();
+    if cwd.is_none() {
+        let mut buf = vec![0 as c_char; libc::PATH_MAX as usize];
+        let ptr = unsafe { (get_original_functions().getcwd)(buf.as_mut_ptr(), buf.len()) };
+        *cwd = unsafe { cstr_to_string(ptr) }.map(|physical| match split_overlay_path(&physical) {
+            Some((_, virtual_path)) => (virtual_path, true),
+            None => (physical, false),
+        });
+    }
+    cwd.clone()
+}
+
+fn invalidate_current_dir() {
+    if let Some(cwd) = CURRENT_DIR.get() {
+        *cwd.lock().unwrap() = None;
+    }
+}
+
+fn join_path(dir: &str, name: &str) -> String {
+    if dir.ends_with('/') {
+        format!("{}{}", dir, name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// Resolves `pathname` as the kernel would for `dirfd` and returns the absolute path used for
+/// blacklist and overlay matching, or `None` if it cannot be determined.
+unsafe fn virtual_path_at(dirfd: c_int, pathname: *const c_char) -> Option<String> {
+    let path = unsafe { cstr_to_string(pathname) }?;
+    if path.starts_with('/') {
+        return Some(normalize_path(&path));
+    }
+    if path.is_empty() {
+        return None;
+    }
+    let base = if dirfd == libc::AT_FDCWD {
+        current_dir()?
+    } else {
+        fd_virtual_path(dirfd)?
+    };
+    Some(normalize_path(&join_path(&base, &path)))
+}
+
+/// Upper bound on symlinks expanded while normalizing one path, mirroring the kernel's ELOOP limit.
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// Collapses duplicate slashes, `.` and `..` in an absolute path so that every spelling of a file
+/// gets the same blacklist and overlay decision.
+///
+/// `..` is applied lexically unless `OBSIDIANOS_OVERLAYS_PHYSICAL_DOTDOT=1`, in which case a
+/// symlink in front of it is expanded first, the way the kernel walks the path.
+fn normalize_path(path: &str) -> String {
+    normalize_path_with(path, is_physical_dotdot_enabled())
+}
+
+fn normalize_path_with(path: &str, physical_dotdot: bool) -> String {
+    let mut components: Vec<String> = Vec::new();
+    let mut pending: Vec<String> = path.split('/').rev().map(String::from).collect();
+    let mut symlinks_expanded = 0;
+    while let Some(component) = pending.pop() {
+        match component.as_str() {
+            "" | "." => {}
+            ".." => {
+                if physical_dotdot && symlinks_expanded < MAX_SYMLINK_DEPTH {
+                    let current = format!("/{}", components.join("/"));
+                    if let Some(target) = read_symlink(&current) {
+                        symlinks_expanded += 1;
+                        if target.starts_with('/') {
+                            components.clear();
+                        } else {
+                            components.pop();
+                        }
+                        pending.push(component);
+                        pending.extend(target.split('/').rev().map(String::from));
+                        continue;
+                    }
+                }
+                components.pop();
+            }
+            _ => components.push(component),
+        }
+    }
+    format!("/{}", components.join("/"))
+}
+
+/// Returns the target of `path` if it is a symlink.
+fn read_symlink(path: &str) -> Option<String> {
+    let path_cstr = CString::new(path).ok()?;
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    let len = unsafe {
+        (get_original_functions().readlink)(path_cstr.as_ptr(), buf.as_mut_ptr() as *mut c_char, buf.len())
+    };
+    if len <= 0 {
+        return None;
+    }
+    buf.truncate(len as usize);
+    String::from_utf8(buf).ok()
+}
+
+fn get_fd_paths() -> &'static Mutex<HashMap<c_int, FdPath>> {
+    FD_PATHS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn fd_identity(fd: c_int) -> Option<libc::stat> {
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { (get_original_functions().fstat)(fd, &mut st) } == 0 {
+        Some(st)
+    } else {
+        None
+    }
+}
+
+/// Records the virtual path of `fd`, along with the identity of the file it refers to so a stale
+/// entry for a reused fd number is recognized.
+fn remember_fd(fd: c_int, path: String) {
+    if let Some(st) = fd_identity(fd) {
+        record_fd(fd, path, &st);
+    }
+}
+
+fn record_fd(fd: c_int, path: String, st: &libc::stat) {
+    if is_verbose_mode_enabled() {
+        eprintln!("[DEBUG] remember_fd: fd {} -> {}", fd, path);
+    }
+    get_fd_paths().lock().unwrap().insert(
+        fd,
+        FdPath {
+            path,
+            dev: st.st_dev,
+            ino: st.st_ino,
+        },
+    );
+}
+
+fn forget_fd(fd: c_int) {
+    if let Some(fd_paths) = FD_PATHS.get() {
+        fd_paths.lock().unwrap().remove(&fd);
+    }
+}
+
+fn copy_fd_path(oldfd: c_int, newfd: c_int) {
+    if let Some(fd_paths) = FD_PATHS.get() {
+        let mut fd_paths = fd_paths.lock().unwrap();
+        match fd_paths.get(&oldfd).cloned() {
+            Some(entry) => {
+                fd_paths.insert(newfd, entry);
+            }
+            None => {
+                fd_paths.remove(&newfd);
+            }
+        }
+    }
+}
+
+/// Records the virtual path behind `fd` after an open of `pathname` for `dirfd` that resolved as
+/// `resolved`. A file redirected into a layer is recorded under the path the layer serves it as,
+/// and a directory is recorded either way so *at() calls through it see the merged view; anything
+/// else is left to the `/proc/self/fd` lookup in `fd_virtual_path`.
+unsafe fn track_fd(fd: c_int, dirfd: c_int, pathname: *const c_char, resolved: &Resolved) -> c_int {
+    if fd < 0 {
+        return fd;
+    }
+    if let Resolved::Redirect(target_cstr) = resolved
+        && let Ok(target) = target_cstr.to_str()
+        && let Some((_, path)) = split_overlay_path(target)
+    {
+        remember_fd(fd, path);
+    } else if let Some(st) = fd_identity(fd)
+        && (st.st_mode & libc::S_IFMT) == libc::S_IFDIR
+        && let Some(path) = unsafe { virtual_path_at(dirfd, pathname) }
+    {
+        record_fd(fd, path, &st);
+    }
+    fd
+}
+
+/// Returns the virtual path of the file or directory behind `fd`.
+///
+/// Files opened through the library are looked up in the fd table; anything else (inherited
+/// fds, fds the table lost track of) is read back from `/proc/self/fd` and mapped out of the
+/// overlay it lives in.
+fn fd_virtual_path(fd: c_int) -> Option<String> {
+    let st = fd_identity(fd)?;
+    {
+        let mut fd_paths = get_fd_paths().lock().unwrap();
+        match fd_paths.get(&fd) {
+            Some(entry) if entry.dev == st.st_dev && entry.ino == st.st_ino => {
+                return Some(entry.path.clone());
+            }
+            Some(_) => {
+                fd_paths.remove(&fd);
+            }
+            None => {}
+        }
+    }
+    let physical = read_symlink(&format!("/proc/self/fd/{}", fd))?;
+    if !physical.starts_with('/') {
+        return None;
+    }
+    Some(physical_to_virtual_path(&physical))
+}
+
+/// Finds the overlay a physical path lies in, returning its position in the layer order and the
+/// path it overlays.
+fn split_overlay_path(path: &str) -> Option<(usize, String)> {
+    for (layer, overlay) in get_overlay_config().iter().enumerate() {
+        let root = overlay.trim_end_matches('/');
+        if root.is_empty() {
+            continue;
+        }
+        if let Some(rest) = path.strip_prefix(root) {
+            if rest.is_empty() {
+                return Some((layer, "/".to_string()));
+            }
+            if rest.starts_with('/') {
+                return Some((layer, rest.to_string()));
+            }
+        }
+    }
+    None
+}
+
+/// Maps a physical path inside one of the overlays back to the path it overlays.
+fn physical_to_virtual_path(path: &str) -> String {
+    split_overlay_path(path).map_or_else(|| path.to_string(), |(_, virtual_path)| virtual_path)
+}
+
+unsafe fn virtual_path(pathname: *const c_char) -> Option<String> {
+    unsafe { virtual_path_at(libc::AT_FDCWD, pathname) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn open(pathname: *const c_char, flags: c_int, mode: mode_t) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().open)(pathname, flags, mode) };
+    }
+    let resolved = unsafe { resolve_open_path_at(libc::AT_FDCWD, pathname, flags) };
+    let fd = unsafe { open_resolved(pathname, &resolved, flags, mode) };
+    unsafe { track_fd(fd, libc::AT_FDCWD, pathname, &resolved) }
+}
+
+unsafe fn open_resolved(
+    pathname: *const c_char,
+    resolved: &Resolved,
+    flags: c_int,
+    mode: mode_t,
+) -> c_int {
+    match resolved {
+        Resolved::Original => unsafe { (get_original_functions().open)(pathname, flags, mode) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().open)(overlay_cstr.as_ptr(), flags, mode)
+        },
+        Resolved::Error(errno) => fail(*errno),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn open64(pathname: *const c_char, flags: c_int, mode: mode_t) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().open64)(pathname, flags, mode) };
+    }
+    let resolved = unsafe { resolve_open_path_at(libc::AT_FDCWD, pathname, flags) };
+    let fd = unsafe { open64_resolved(pathname, &resolved, flags, mode) };
+    unsafe { track_fd(fd, libc::AT_FDCWD, pathname, &resolved) }
+}
+
+unsafe fn open64_resolved(
+    pathname: *const c_char,
+    resolved: &Resolved,
+    flags: c_int,
+    mode: mode_t,
+) -> c_int {
+    match resolved {
+        Resolved::Original => unsafe { (get_original_functions().open64)(pathname, flags, mode) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().open64)(overlay_cstr.as_ptr(), flags, mode)
+        },
+        Resolved::Error(errno) => fail(*errno),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn openat(
+    dirfd: c_int,
+    pathname: *const c_char,
+    flags: c_int,
+    mode: mode_t,
+) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().openat)(dirfd, pathname, flags, mode) };
+    }
+    let resolved = unsafe { resolve_open_path_at(dirfd, pathname, flags) };
+    let fd = unsafe { openat_resolved(dirfd, pathname, &resolved, flags, mode) };
+    unsafe { track_fd(fd, dirfd, pathname, &resolved) }
+}
+
+unsafe fn openat_resolved(
+    dirfd: c_int,
+    pathname: *const c_char,
+    resolved: &Resolved,
+    flags: c_int,
+    mode: mode_t,
+) -> c_int {
+    match resolved {
+        Resolved::Original => unsafe {
+            (get_original_functions().openat)(dirfd, pathname, flags, mode)
+        },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().openat)(dirfd, overlay_cstr.as_ptr(), flags, mode)
+        },
+        Resolved::Error(errno) => fail(*errno),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn openat64(
+    dirfd: c_int,
+    pathname: *const c_char,
+    flags: c_int,
+    mode: mode_t,
+) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().openat64)(dirfd, pathname, flags, mode) };
+    }
+    let resolved = unsafe { resolve_open_path_at(dirfd, pathname, flags) };
+    let fd = unsafe { openat64_resolved(dirfd, pathname, &resolved, flags, mode) };
+    unsafe { track_fd(fd, dirfd, pathname, &resolved) }
+}
+
+unsafe fn openat64_resolved(
+    dirfd: c_int,
+    pathname: *const c_char,
+    resolved: &Resolved,
+    flags: c_int,
+    mode: mode_t,
+) -> c_int {
+    match resolved {
+        Resolved::Original => unsafe {
+            (get_original_functions().openat64)(dirfd, pathname, flags, mode)
+        },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().openat64)(dirfd, overlay_cstr.as_ptr(), flags, mode)
+        },
+        Resolved::Error(errno) => fail(*errno),
+    }
+}
+
+/// Whether `flags` need the mode argument that the fortified `open` variants don't take.
+fn needs_mode(flags: c_int) -> bool {
+    (flags & libc::O_CREAT) != 0 || (flags & libc::O_TMPFILE) == libc::O_TMPFILE
+}
+
+// `_FORTIFY_SOURCE` builds call these when the mode argument is left out. Flags that need a mode
+// go to glibc, which aborts the program for them.
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __open_2(pathname: *const c_char, flags: c_int) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) || needs_mode(flags) {
+        return unsafe { (get_original_functions().open_2)(pathname, flags) };
+    }
+    unsafe { open(pathname, flags, 0) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __open64_2(pathname: *const c_char, flags: c_int) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) || needs_mode(flags) {
+        return unsafe { (get_original_functions().open64_2)(pathname, flags) };
+    }
+    unsafe { open64(pathname, flags, 0) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __openat_2(dirfd: c_int, pathname: *const c_char, flags: c_int) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) || needs_mode(flags) {
+        return unsafe { (get_original_functions().openat_2)(dirfd, pathname, flags) };
+    }
+    unsafe { openat(dirfd, pathname, flags, 0) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __openat64_2(
+    dirfd: c_int,
+    pathname: *const c_char,
+    flags: c_int,
+) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) || needs_mode(flags) {
+        return unsafe { (get_original_functions().openat64_2)(dirfd, pathname, flags) };
+    }
+    unsafe { openat64(dirfd, pathname, flags, 0) }
+}
+
+/// `RESOLVE_*` flags that confine an `openat2` lookup to its dirfd's tree. They are enforced in the
+/// merged view, so a call redirected to a physical path drops them; the overlays themselves don't
+/// count as mount crossings for `RESOLVE_NO_XDEV`.
+const RESOLVE_SCOPE: u64 = libc::RESOLVE_BENEATH | libc::RESOLVE_IN_ROOT | libc::RESOLVE_NO_XDEV;
+
+unsafe fn real_openat2(
+    dirfd: c_int,
+    pathname: *const c_char,
+    how: *mut libc::open_how,
+    size: size_t,
+) -> c_int {
+    match get_original_functions().openat2 {
+        Some(original_openat2) => unsafe { original_openat2(dirfd, pathname, how, size) },
+        None => unsafe {
+            (get_original_functions().syscall)(
+                libc::SYS_openat2,
+                dirfd as c_long,
+                pathname as c_long,
+                how as c_long,
+                size as c_long,
+            ) as c_int
+        },
+    }
+}
+
+/// Whether the kernel has `openat2`, checked once with a call on an invalid fd that can only fail.
+fn is_openat2_supported() -> bool {
+    *OPENAT2_SUPPORTED.get_or_init(|| {
+        let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+        let size = std::mem::size_of::<libc::open_how>();
+        let result = unsafe { real_openat2(-1, c"probe".as_ptr(), &mut how, size) };
+        result >= 0 || last_errno() != libc::ENOSYS
+    })
+}
+
+/// Joins `path` onto `root`, applying `..` lexically without ever leaving `root`.
+fn join_under_root(root: &str, path: &str) -> String {
+    let path = normalize_path(&format!("/{}", path));
+    match (root, path.as_str()) {
+        ("/", _) => path,
+        (_, "/") => root.to_string(),
+        _ => format!("{}{}", root, path),
+    }
+}
+
+fn is_beneath(path: &str, dir: &str) -> bool {
+    dir == "/" || path == dir || path.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Whether looking up the relative `path` from `depth` levels below the starting directory steps
+/// above that directory at some `..`, even if later components lead back into it.
+fn climbs_out(path: &str, depth: usize) -> bool {
+    let mut depth = depth;
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." if depth == 0 => return true,
+            ".." => depth -= 1,
+            _ => depth += 1,
+        }
+    }
+    false
+}
+
+/// Looks up `path` for `openat2` relative to the virtual directory `dir` under the `RESOLVE_*`
+/// flags in `resolve`, expanding the symlinks that overlays hold like `resolve_overlay_symlinks`.
+/// `RESOLVE_IN_ROOT` makes `dir` the root for absolute paths, symlink targets and `..`,
+/// `RESOLVE_BENEATH` fails with `EXDEV` as soon as the lookup would leave `dir`, and
+/// `RESOLVE_NO_SYMLINKS` fails with `ELOOP` on any overlay symlink. Returns the virtual path and
+/// whether any symlink was expanded. A redirected call reaches the kernel without the scope flags,
+/// so under `RESOLVE_BENEATH` or `RESOLVE_IN_ROOT` base filesystem symlinks are expanded here as
+/// well; otherwise they are left to the kernel.
+fn resolve_openat2_path(
+    dir: &str,
+    path: &str,
+    resolve: u64,
+    follow: bool,
+) -> Result<(String, bool), c_int> {
+    let beneath = (resolve & libc::RESOLVE_BENEATH) != 0;
+    if beneath && (path.starts_with('/') || climbs_out(path, 0)) {
+        return Err(libc::EXDEV);
+    }
+    let root = if (resolve & libc::RESOLVE_IN_ROOT) != 0 { dir } else { "/" };
+    let mut current = if root == "/" && !path.starts_with('/') {
+        normalize_path(&join_path(dir, path))
+    } else {
+        join_under_root(root, path)
+    };
+    let confined = (resolve & (libc::RESOLVE_BENEATH | libc::RESOLVE_IN_ROOT)) != 0;
+    let mut expanded = 0;
+    loop {
+        if beneath && !is_beneath(&current, dir) {
+            return Err(libc::EXDEV);
+        }
+        let symlink = if confined {
+            find_merged_symlink(&current, follow)
+        } else if get_blacklist().lock().unwrap().is_blacklisted(&current) {
+            None
+        } else {
+            find_overlay_symlink(&current, follow)
+        };
+        let Some((end, target)) = symlink else {
+            break;
+        };
+        if (resolve & libc::RESOLVE_NO_SYMLINKS) != 0 || expanded == MAX_SYMLINK_DEPTH {
+            return Err(libc::ELOOP);
+        }
+        let link_dir = if target.starts_with('/') { "" } else { parent_path(&current[..end]) };
+        if beneath {
+            let below_dir = link_dir.strip_prefix(dir).unwrap_or(link_dir);
+            let link_depth = below_dir.split('/').filter(|c| !c.is_empty()).count();
+            if target.starts_with('/') || climbs_out(&target, link_depth) {
+                return Err(libc::EXDEV);
+            }
+        }
+        expanded += 1;
+        let link_dir = link_dir.strip_prefix(root).unwrap_or(link_dir);
+        current = join_under_root(root, &format!("{}/{}{}", link_dir, target, &current[end..]));
+    }
+    Ok((current, expanded > 0))
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn openat2(
+    dirfd: c_int,
+    pathname: *const c_char,
+    how: *mut libc::open_how,
+    size: size_t,
+) -> c_int {
+    // Malformed arguments, including a `struct open_how` larger than the kernel's one-page limit,
+    // get the kernel's own error.
+    if INIT_GUARD.load(Ordering::Relaxed)
+        || how.is_null()
+        || size < std::mem::size_of::<libc::open_how>()
+        || size > 4096
+    {
+        return unsafe { real_openat2(dirfd, pathname, how, size) };
+    }
+    // Callers fall back to openat on ENOSYS, which must come before anything is copied up.
+    if !is_openat2_supported() {
+        return fail(libc::ENOSYS);
+    }
+    let open_how = unsafe { *how };
+    let Ok(flags) = c_int::try_from(open_how.flags) else {
+        return unsafe { real_openat2(dirfd, pathname, how, size) };
+    };
+    let Some(path) = (unsafe { cstr_to_string(pathname) }).filter(|path| !path.is_empty()) else {
+        return unsafe { real_openat2(dirfd, pathname, how, size) };
+    };
+    let from_root = path.starts_with('/') && (open_how.resolve & libc::RESOLVE_IN_ROOT) == 0;
+    let dir = if from_root {
+        Some("/".to_string())
+    } else if dirfd == libc::AT_FDCWD {
+        current_dir()
+    } else {
+        fd_virtual_path(dirfd)
+    };
+    let Some(dir) = dir else {
+        return unsafe { real_openat2(dirfd, pathname, how, size) };
+    };
+    let follow = (flags & libc::O_NOFOLLOW) == 0 || unsafe { has_trailing_slash(pathname) };
+    let (target, expanded) = match resolve_openat2_path(&dir, &path, open_how.resolve, follow) {
+        Ok(result) => result,
+        Err(errno) => return fail(errno),
+    };
+    let target_cstr = CString::new(target.as_str()).unwrap();
+    let resolved = unsafe { resolve_open_path_at(libc::AT_FDCWD, target_cstr.as_ptr(), flags) };
+    // The kernel would start from the physical directory of an overlay, outside the merged view.
+    let relocated = expanded || (!from_root && is_dirfd_in_overlay(dirfd));
+    let physical = match resolved {
+        Resolved::Original if !relocated => None,
+        Resolved::Original => Some(target_cstr),
+        Resolved::Redirect(overlay_cstr) => Some(overlay_cstr),
+        Resolved::Error(errno) => return fail(errno),
+    };
+    let fd = match physical {
+        None => unsafe { real_openat2(dirfd, pathname, how, size) },
+        Some(physical_cstr) => {
+            if is_verbose_mode_enabled() {
+                eprintln!(
+                    "[*] ObsidianOS Overlays: {} -> {}",
+                    target,
+                    physical_cstr.to_string_lossy()
+                );
+            }
+            // Pass on any fields of a newer `struct open_how` untouched.
+            let mut how_buf = vec![0u64; size.div_ceil(8)];
+            let how_copy = how_buf.as_mut_ptr() as *mut libc::open_how;
+            unsafe {
+                std::ptr::copy_nonoverlapping(how as *const u8, how_copy as *mut u8, size);
+                (*how_copy).resolve &= !RESOLVE_SCOPE;
+            }
+            unsafe { real_openat2(libc::AT_FDCWD, physical_cstr.as_ptr(), how_copy, size) }
+        }
+    };
+    if fd >= 0 {
+        remember_fd(fd, target);
+    }
+    fd
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fopen(pathname: *const c_char, mode: *const c_char) -> *mut FILE {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().fopen)(pathname, mode) };
+    }
+    match unsafe { resolve_open_path_at(libc::AT_FDCWD, pathname, fopen_flags(mode)) } {
+        Resolved::Original => unsafe { (get_original_functions().fopen)(pathname, mode) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().fopen)(overlay_cstr.as_ptr(), mode)
+        },
+        Resolved::Error(errno) => {
+            set_errno(errno);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fopen64(pathname: *const c_char, mode: *const c_char) -> *mut FILE {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().fopen64)(pathname, mode) };
+    }
+    match unsafe { resolve_open_path_at(libc::AT_FDCWD, pathname, fopen_flags(mode)) } {
+        Resolved::Original => unsafe { (get_original_functions().fopen64)(pathname, mode) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().fopen64)(overlay_cstr.as_ptr(), mode)
+        },
+        Resolved::Error(errno) => {
+            set_errno(errno);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// The parts of a stat-family result buffer that the merged view adjusts.
+trait StatBuf {
+    fn is_dir(&self) -> bool;
+    fn set_nlink(&mut self, nlink: u64);
+    fn dev_ino(&self) -> (u64, u64);
+    fn set_dev_ino(&mut self, dev: u64, ino: u64);
+}
+
+impl StatBuf for libc::stat {
+    fn is_dir(&self) -> bool {
+        (self.st_mode & libc::S_IFMT) == libc::S_IFDIR
+    }
+    fn set_nlink(&mut self, nlink: u64) {
+        self.st_nlink = nlink as libc::nlink_t;
+    }
+    // `ino_t` is 32 bits wide in the `struct stat` of 32-bit targets.
+    #[allow(clippy::unnecessary_cast)]
+    fn dev_ino(&self) -> (u64, u64) {
+        (self.st_dev, self.st_ino as u64)
+    }
+    fn set_dev_ino(&mut self, dev: u64, ino: u64) {
+        self.st_dev = dev;
+        self.st_ino = ino as libc::ino_t;
+    }
+}
+
+impl StatBuf for libc::stat64 {
+    fn is_dir(&self) -> bool {
+        (self.st_mode & libc::S_IFMT) == libc::S_IFDIR
+    }
+    fn set_nlink(&mut self, nlink: u64) {
+        self.st_nlink = nlink as libc::nlink_t;
+    }
+    fn dev_ino(&self) -> (u64, u64) {
+        (self.st_dev, self.st_ino)
+    }
+    fn set_dev_ino(&mut self, dev: u64, ino: u64) {
+        self.st_dev = dev;
+        self.st_ino = ino;
+    }
+}
+
+impl StatBuf for libc::statx {
+    fn is_dir(&self) -> bool {
+        (self.stx_mode as mode_t & libc::S_IFMT) == libc::S_IFDIR
+    }
+    fn set_nlink(&mut self, nlink: u64) {
+        self.stx_nlink = nlink as u32;
+    }
+    fn dev_ino(&self) -> (u64, u64) {
+        (libc::makedev(self.stx_dev_major, self.stx_dev_minor), self.stx_ino)
+    }
+    fn set_dev_ino(&mut self, dev: u64, ino: u64) {
+        self.stx_dev_major = libc::major(dev);
+        self.stx_dev_minor = libc::minor(dev);
+        self.stx_ino = ino;
+    }
+}
+
+/// Link count of `path` if it is a directory merged from more than one layer: two plus the number
+/// of subdirectories in the merged view, as on a single filesystem, so tools like `find` that
+/// count subdirectories by link count see all of them.
+fn merged_dir_nlink(path: &str) -> Option<u64> {
+    let (overlay_dirs, base_visible) = find_overlay_dirs(path);
+    let base_dir = base_visible
+        && lstat_physical(path).is_some_and(|st| (st.st_mode & libc::S_IFMT) == libc::S_IFDIR);
+    if overlay_dirs.len() + usize::from(base_dir) < 2 {
+        return None;
+    }
+    let subdirs = unsafe { read_merged_entries(path) }
+        .iter()
+        .filter(|entry| entry.name != b"." && entry.name != b"..")
+        .filter(|entry| match entry.d_type {
+            libc::DT_DIR => true,
+            libc::DT_UNKNOWN => {
+                let child = join_path(path, &String::from_utf8_lossy(&entry.name));
+                visible_source(&child)
+                    .ok()
+                    .and_then(|source| lstat_physical(&source))
+                    .is_some_and(|st| (st.st_mode & libc::S_IFMT) == libc::S_IFDIR)
+            }
+            _ => false,
+        })
+        .count();
+    Some(2 + subdirs as u64)
+}
+
+/// Inode number an entry of overlay `layer` is presented with when inodes are virtualized: its real
+/// one tagged with the layer in the top byte, which keeps the entries of different layers apart on
+/// the merged tree's single device. Hard links within a layer keep sharing an inode number.
+fn virtual_ino(layer: usize, ino: u64) -> u64 {
+    ((layer as u64 + 1) << 56) | (ino & ((1 << 56) - 1))
+}
+
+/// Device the merged tree presents at `path`: that of the base filesystem at its deepest existing
+/// ancestor.
+fn merged_dev(path: &str) -> Option<u64> {
+    let mut ancestor = path;
+    loop {
+        if let Some(st) = lstat_physical(ancestor) {
+            return Some(st.st_dev);
+        }
+        if ancestor == "/" {
+            return None;
+        }
+        ancestor = parent_path(ancestor);
+    }
+}
+
+/// Rewrites the device and inode in `buf`, the result of a stat on the overlay entry `physical`,
+/// to their virtual values. Results that describe something else, like the target of a symlink
+/// that was followed, are left alone.
+fn virtualize_inode<T: StatBuf>(physical: &str, buf: &mut T) {
+    let Some((layer, path)) = split_overlay_path(physical) else {
+        return;
+    };
+    let Some(entry) = lstat_physical(physical) else {
+        return;
+    };
+    let (dev, ino) = buf.dev_ino();
+    if (dev, ino) != entry.dev_ino() {
+        return;
+    }
+    if let Some(merged) = merged_dev(&path) {
+        buf.set_dev_ino(merged, virtual_ino(layer, ino));
+    }
+}
+
+/// Physical path of the file behind `fd`, if it is one.
+fn fd_physical_path(fd: c_int) -> Option<String> {
+    read_symlink(&format!("/proc/self/fd/{}", fd)).filter(|path| path.starts_with('/'))
+}
+
+/// Shared body of every stat-family interposer, so they all give the same answer for a path.
+/// `call` runs the real function on a dirfd and path: the caller's own, or the physical path of
+/// the topmost layer holding the entry. Symlinks in overlays are expanded in the merged view, the
+/// final one only if `follow`, which must match whether `call` follows symlinks. A merged
+/// directory reports the metadata of its topmost layer with the link count of the merged view.
+unsafe fn stat_resolved<T: StatBuf>(
+    dirfd: c_int,
+    pathname: *const c_char,
+    buf: *mut T,
+    follow: bool,
+    call: impl FnOnce(c_int, *const c_char) -> c_int,
+) -> c_int {
+    // An empty path (`AT_EMPTY_PATH`) stats `dirfd` itself.
+    let Some(path_str) = (unsafe { virtual_path_at(dirfd, pathname) }) else {
+        let result = call(dirfd, pathname);
+        if result == 0
+            && !buf.is_null()
+            && is_virtual_inodes_enabled()
+            && let Some(physical) = fd_physical_path(dirfd)
+        {
+            virtualize_inode(&physical, unsafe { &mut *buf });
+        }
+        return result;
+    };
+    let follow = follow || unsafe { has_trailing_slash(pathname) };
+    let target = match resolve_overlay_symlinks(&path_str, follow) {
+        Ok(target) => target,
+        Err(errno) => return fail(errno),
+    };
+    let target_cstr = CString::new(target.as_str()).unwrap();
+    let relocated = target != path_str || unsafe { is_relative_to_overlay(dirfd, pathname) };
+    let (dirfd, pathname) = if !relocated {
+        (dirfd, pathname)
+    } else {
+        (libc::AT_FDCWD, target_cstr.as_ptr())
+    };
+    if get_blacklist().lock().unwrap().is_blacklisted(&target) {
+        return call(dirfd, pathname);
+    }
+    let result = match find_overlay_entry(&target) {
+        OverlayLookup::Found(overlay_path) => {
+            if is_verbose_mode_enabled() {
+                eprintln!("[*] ObsidianOS Overlays: {} -> {}", path_str, overlay_path);
+            }
+            let overlay_cstr = CString::new(overlay_path.as_str()).unwrap();
+            let result = call(libc::AT_FDCWD, overlay_cstr.as_ptr());
+            if result == 0 && !buf.is_null() && is_virtual_inodes_enabled() {
+                virtualize_inode(&overlay_path, unsafe { &mut *buf });
+            }
+            result
+        }
+        OverlayLookup::Hidden => return fail(libc::ENOENT),
+        OverlayLookup::Base => call(dirfd, pathname),
+    };
+    if result == 0
+        && !buf.is_null()
+        && unsafe { &*buf }.is_dir()
+        && let Some(nlink) = merged_dir_nlink(&target)
+    {
+        unsafe { &mut *buf }.set_nlink(nlink);
+    }
+    result
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn stat(pathname: *const c_char, statbuf: *mut libc::stat) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().stat)(pathname, statbuf) };
+    }
+    unsafe {
+        stat_resolved(libc::AT_FDCWD, pathname, statbuf, true, |_, path| {
+            (get_original_functions().stat)(path, statbuf)
+        })
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lstat(pathname: *const c_char, statbuf: *mut libc::stat) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().lstat)(pathname, statbuf) };
+    }
+    unsafe {
+        stat_resolved(libc::AT_FDCWD, pathname, statbuf, false, |_, path| {
+            (get_original_functions().lstat)(path, statbuf)
+        })
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn stat64(pathname: *const c_char, statbuf: *mut libc::stat64) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().stat64)(pathname, statbuf) };
+    }
+    unsafe {
+        stat_resolved(libc::AT_FDCWD, pathname, statbuf, true, |_, path| {
+            (get_original_functions().stat64)(path, statbuf)
+        })
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lstat64(pathname: *const c_char, statbuf: *mut libc::stat64) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().lstat64)(pathname, statbuf) };
+    }
+    unsafe {
+        stat_resolved(libc::AT_FDCWD, pathname, statbuf, false, |_, path| {
+            (get_original_functions().lstat64)(path, statbuf)
+        })
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fstatat(
+    dirfd: c_int,
+    pathname: *const c_char,
+    statbuf: *mut libc::stat,
+    flags: c_int,
+) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().fstatat)(dirfd, pathname, statbuf, flags) };
+    }
+    let follow = (flags & libc::AT_SYMLINK_NOFOLLOW) == 0;
+    unsafe {
+        stat_resolved(dirfd, pathname, statbuf, follow, |dirfd, path| {
+            (get_original_functions().fstatat)(dirfd, path, statbuf, flags)
+        })
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fstatat64(
+    dirfd: c_int,
+    pathname: *const c_char,
+    statbuf: *mut libc::stat64,
+    flags: c_int,
+) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().fstatat64)(dirfd, pathname, statbuf, flags) };
+    }
+    let follow = (flags & libc::AT_SYMLINK_NOFOLLOW) == 0;
+    unsafe {
+        stat_resolved(dirfd, pathname, statbuf, follow, |dirfd, path| {
+            (get_original_functions().fstatat64)(dirfd, path, statbuf, flags)
+        })
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fstat(fd: c_int, statbuf: *mut libc::stat) -> c_int {
+    let result = unsafe { (get_original_functions().fstat)(fd, statbuf) };
+    if result == 0
+        && !statbuf.is_null()
+        && !INIT_GUARD.load(Ordering::Relaxed)
+        && is_virtual_inodes_enabled()
+        && let Some(physical) = fd_physical_path(fd)
+    {
+        virtualize_inode(&physical, unsafe { &mut *statbuf });
+    }
+    result
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fstat64(fd: c_int, statbuf: *mut libc::stat64) -> c_int {
+    let result = unsafe { (get_original_functions().fstat64)(fd, statbuf) };
+    if result == 0
+        && !statbuf.is_null()
+        && !INIT_GUARD.load(Ordering::Relaxed)
+        && is_virtual_inodes_enabled()
+        && let Some(physical) = fd_physical_path(fd)
+    {
+        virtualize_inode(&physical, unsafe { &mut *statbuf });
+    }
+    result
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn statx(
+    dirfd: c_int,
+    pathname: *const c_char,
+    flags: c_int,
+    mask: c_uint,
+    statxbuf: *mut libc::statx,
+) -> c_int {
+    let Some(original_statx) = get_original_functions().statx else {
+        return fail(libc::ENOSYS);
+    };
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { original_statx(dirfd, pathname, flags, mask, statxbuf) };
+    }
+    let follow = (flags & libc::AT_SYMLINK_NOFOLLOW) == 0;
+    unsafe {
+        stat_resolved(dirfd, pathname, statxbuf, follow, |dirfd, path| {
+            original_statx(dirfd, path, flags, mask, statxbuf)
+        })
+    }
+}
+
+// Binaries linked against glibc before 2.33 call these versioned wrappers instead of `stat` and
+// friends. On 64-bit targets every accepted version uses the layout `struct stat` already has, so
+// they forward to the interposers above; 32-bit targets keep the versioned layouts and are left to
+// glibc.
+
+/// `_STAT_VER_KERNEL` and `_STAT_VER_LINUX`, the layout versions glibc's `__xstat` family accepts.
+/// The generic ABI of aarch64, riscv64 and loongarch64 defines both as 0.
+#[cfg(target_pointer_width = "64")]
+const STAT_VER_KERNEL: c_int = 0;
+#[cfg(all(
+    target_pointer_width = "64",
+    any(target_arch = "x86_64", target_arch = "powerpc64", target_arch = "s390x")
+))]
+const STAT_VER_LINUX: c_int = 1;
+#[cfg(all(
+    target_pointer_width = "64",
+    not(any(target_arch = "x86_64", target_arch = "powerpc64", target_arch = "s390x"))
+))]
+const STAT_VER_LINUX: c_int = 0;
+
+/// Whether `ver` is a layout version the `__xstat` family accepts; glibc fails any other with
+/// `EINVAL` rather than guess at the caller's `struct stat`.
+#[cfg(target_pointer_width = "64")]
+fn is_known_stat_ver(ver: c_int) -> bool {
+    ver == STAT_VER_KERNEL || ver == STAT_VER_LINUX
+}
+
+#[cfg(target_pointer_width = "64")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __xstat(
+    ver: c_int,
+    pathname: *const c_char,
+    statbuf: *mut libc::stat,
+) -> c_int {
+    if !is_known_stat_ver(ver) {
+        return fail(libc::EINVAL);
+    }
+    unsafe { stat(pathname, statbuf) }
+}
+
+#[cfg(target_pointer_width = "64")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __lxstat(
+    ver: c_int,
+    pathname: *const c_char,
+    statbuf: *mut libc::stat,
+) -> c_int {
+    if !is_known_stat_ver(ver) {
+        return fail(libc::EINVAL);
+    }
+    unsafe { lstat(pathname, statbuf) }
+}
+
+#[cfg(target_pointer_width = "64")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __fxstat(ver: c_int, fd: c_int, statbuf: *mut libc::stat) -> c_int {
+    if !is_known_stat_ver(ver) {
+        return fail(libc::EINVAL);
+    }
+    unsafe { fstat(fd, statbuf) }
+}
+
+#[cfg(target_pointer_width = "64")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __fxstatat(
+    ver: c_int,
+    dirfd: c_int,
+    pathname: *const c_char,
+    statbuf: *mut libc::stat,
+    flags: c_int,
+) -> c_int {
+    if !is_known_stat_ver(ver) {
+        return fail(libc::EINVAL);
+    }
+    unsafe { fstatat(dirfd, pathname, statbuf, flags) }
+}
+
+#[cfg(target_pointer_width = "64")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __xstat64(
+    ver: c_int,
+    pathname: *const c_char,
+    statbuf: *mut libc::stat64,
+) -> c_int {
+    if !is_known_stat_ver(ver) {
+        return fail(libc::EINVAL);
+    }
+    unsafe { stat64(pathname, statbuf) }
+}
+
+#[cfg(target_pointer_width = "64")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __lxstat64(
+    ver: c_int,
+    pathname: *const c_char,
+    statbuf: *mut libc::stat64,
+) -> c_int {
+    if !is_known_stat_ver(ver) {
+        return fail(libc::EINVAL);
+    }
+    unsafe { lstat64(pathname, statbuf) }
+}
+
+#[cfg(target_pointer_width = "64")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __fxstat64(ver: c_int, fd: c_int, statbuf: *mut libc::stat64) -> c_int {
+    if !is_known_stat_ver(ver) {
+        return fail(libc::EINVAL);
+    }
+    unsafe { fstat64(fd, statbuf) }
+}
+
+#[cfg(target_pointer_width = "64")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __fxstatat64(
+    ver: c_int,
+    dirfd: c_int,
+    pathname: *const c_char,
+    statbuf: *mut libc::stat64,
+    flags: c_int,
+) -> c_int {
+    if !is_known_stat_ver(ver) {
+        return fail(libc::EINVAL);
+    }
+    unsafe { fstatat64(dirfd, pathname, statbuf, flags) }
+}
+
+/// Applies read-only prefixes to the `result` of an access check: asking for `W_OK` on anything
+/// but a special file fails with `EROFS` there, ahead of the permission check, as on a read-only
+/// mount.
+unsafe fn read_only_access(
+    dirfd: c_int,
+    pathname: *const c_char,
+    mode: c_int,
+    follow: bool,
+    result: c_int,
+) -> c_int {
+    if (mode & libc::W_OK) == 0 || (result != 0 && last_errno() != libc::EACCES) {
+        return result;
+    }
+    let read_only = unsafe { virtual_path_at(dirfd, pathname) }
+        .and_then(|path| resolve_overlay_symlinks(&path, follow).ok())
+        .is_some_and(|path| is_read_only(&path));
+    if read_only && !unsafe { is_special_path_at(dirfd, pathname, follow) } {
+        return fail(libc::EROFS);
+    }
+    result
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn access(pathname: *const c_char, mode: c_int) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().access)(pathname, mode) };
+    }
+    let result = match unsafe { resolve_path(pathname) } {
+        Resolved::Original => unsafe { (get_original_functions().access)(pathname, mode) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().access)(overlay_cstr.as_ptr(), mode)
+        },
+        Resolved::Error(errno) => return fail(errno),
+    };
+    unsafe { read_only_access(libc::AT_FDCWD, pathname, mode, true, result) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn faccessat(
+    dirfd: c_int,
+    pathname: *const c_char,
+    mode: c_int,
+    flags: c_int,
+) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().faccessat)(dirfd, pathname, mode, flags) };
+    }
+    // `AT_EACCESS` only changes which ids the kernel checks against, so it is passed on as is.
+    let follow = (flags & libc::AT_SYMLINK_NOFOLLOW) == 0;
+    let result = match unsafe { resolve_lookup_at(dirfd, pathname, follow) } {
+        Resolved::Original => unsafe {
+            (get_original_functions().faccessat)(dirfd, pathname, mode, flags)
+        },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().faccessat)(dirfd, overlay_cstr.as_ptr(), mode, flags)
+        },
+        Resolved::Error(errno) => return fail(errno),
+    };
+    unsafe { read_only_access(dirfd, pathname, mode, follow, result) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn readlink(
+    pathname: *const c_char,
+    buf: *mut c_char,
+    bufsiz: size_t,
+) -> ssize_t {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().readlink)(pathname, buf, bufsiz) };
+    }
+    if let Some(target) = unsafe { virtual_path(pathname) }.and_then(|path| proc_link_target(&path)) {
+        return unsafe { copy_link_out(&target, buf, bufsiz) };
+    }
+    match unsafe { resolve_link_path_at(libc::AT_FDCWD, pathname) } {
+        Resolved::Original => unsafe { (get_original_functions().readlink)(pathname, buf, bufsiz) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().readlink)(overlay_cstr.as_ptr(), buf, bufsiz)
+        },
+        Resolved::Error(errno) => fail(errno) as ssize_t,
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn readlinkat(
+    dirfd: c_int,
+    pathname: *const c_char,
+    buf: *mut c_char,
+    bufsiz: size_t,
+) -> ssize_t {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().readlinkat)(dirfd, pathname, buf, bufsiz) };
+    }
+    if let Some(target) =
+        unsafe { virtual_path_at(dirfd, pathname) }.and_then(|path| proc_link_target(&path))
+    {
+        return unsafe { copy_link_out(&target, buf, bufsiz) };
+    }
+    match unsafe { resolve_link_path_at(dirfd, pathname) } {
+        Resolved::Original => unsafe {
+            (get_original_functions().readlinkat)(dirfd, pathname, buf, bufsiz)
+        },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().readlinkat)(dirfd, overlay_cstr.as_ptr(), buf, bufsiz)
+        },
+        Resolved::Error(errno) => fail(errno) as ssize_t,
+    }
+}
+
+/// Returns the virtual target of one of this process's `/proc` magic links that points into an
+/// overlay: `exe`, `cwd` or `fd/<n>`, under `/proc/self`, `/proc/thread-self` or `/proc/<pid>`, or
+/// `/dev/fd/<n>`. `None` leaves the link to the kernel, as for anything outside the overlays.
+fn proc_link_target(path: &str) -> Option<String> {
+    let link = match path.strip_prefix("/dev/fd/") {
+        Some(fd) => format!("fd/{}", fd),
+        None => {
+            let (pid, link) = path.strip_prefix("/proc/")?.split_once('/')?;
+            if pid != "self" && pid != "thread-self" && pid != std::process::id().to_string() {
+                return None;
+            }
+            link.to_string()
+        }
+    };
+    let physical = read_symlink(&format!("/proc/self/{}", link))?;
+    split_overlay_path(&physical)?;
+    match link.as_str() {
+        "exe" => Some(exec_virtual_path(&physical).unwrap_or_else(|| physical_to_virtual_path(&physical))),
+        "cwd" => current_dir(),
+        _ => fd_virtual_path(link.strip_prefix("fd/")?.parse().ok()?),
+    }
+}
+
+/// The virtual path recorded for the running program by the exec that started it, if it still
+/// describes the program at `physical`.
+fn exec_virtual_path(physical: &str) -> Option<String> {
+    let recorded = env::var(EXEC_PATH_ENV).ok()?;
+    (visible_source(&recorded).ok()? == physical).then_some(recorded)
+}
+
+/// Returns the virtual path `pathname` execs, with symlinks in overlays expanded like the kernel
+/// expands them for `/proc/self/exe`.
+unsafe fn exec_target(pathname: *const c_char) -> Option<String> {
+    unsafe { virtual_path(pathname) }.and_then(|path| resolve_overlay_symlinks(&path, true).ok())
+}
+
+/// Copies a symlink target out the way `readlink` does: truncated to `bufsiz` bytes and without
+/// a terminating NUL.
+unsafe fn copy_link_out(target: &str, buf: *mut c_char, bufsiz: size_t) -> ssize_t {
+    let len = target.len().min(bufsiz);
+    unsafe { std::ptr::copy_nonoverlapping(target.as_ptr() as *const c_char, buf, len) };
+    len as ssize_t
+}
+
+/// Returns `envp` with what the library passes on to a new program: the session's ephemeral
+/// layer, if there is one and `envp` doesn't pass it on already, so a program started with a
+/// custom environment still writes to it, and the program's virtual path if the exec was
+/// redirected. `None` means `envp` can be used as it is.
+unsafe fn with_session_env(
+    envp: *const *const c_char,
+    exec_path: Option<&str>,
+) -> Option<(Vec<CString>, Vec<*const c_char>)> {
+    let mut ptrs = Vec::new();
+    let mut has_ephemeral_dir = false;
+    if !envp.is_null() {
+        let mut i = 0;
+        loop {
+            let entry = unsafe { *envp.add(i) };
+            if entry.is_null() {
+                break;
+            }
+            i += 1;
+            let bytes = unsafe { CStr::from_ptr(entry) }.to_bytes();
+            has_ephemeral_dir |= bytes.starts_with(format!("{}=", EPHEMERAL_DIR_ENV).as_bytes());
+            if bytes.starts_with(format!("{}=", EXEC_PATH_ENV).as_bytes()) {
+                continue;
+            }
+            ptrs.push(entry);
+        }
+    }
+    let mut added = Vec::new();
+    if let Some(dir) = get_ephemeral_dir()
+        && !has_ephemeral_dir
+    {
+        let owner = env::var(EPHEMERAL_OWNER_ENV).unwrap_or_default();
+        added.push(CString::new(format!("{}={}", EPHEMERAL_DIR_ENV, dir)).ok()?);
+        added.push(CString::new(format!("{}={}", EPHEMERAL_OWNER_ENV, owner)).ok()?);
+    }
+    if let Some(path) = exec_path {
+        added.push(CString::new(format!("{}={}", EXEC_PATH_ENV, path)).ok()?);
+    }
+    if added.is_empty() {
+        return None;
+    }
+    ptrs.extend(added.iter().map(|entry| entry.as_ptr()));
+    ptrs.push(std::ptr::null());
+    Some((added, ptrs))
+}
+
+/// The calling process's environment, for the `exec` variants that take it from there. They go
+/// through the explicit-environment calls so the session's additions never touch the process's own.
+fn caller_environ() -> *const *const c_char {
+    unsafe extern "C" {
+        static environ: *const *const c_char;
+    }
+    unsafe { environ }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn execve(
+    pathname: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().execve)(pathname, argv, envp) };
+    }
+    let resolved = unsafe { resolve_path(pathname) };
+    let exec_path = match resolved {
+        Resolved::Redirect(_) => unsafe { exec_target(pathname) },
+        _ => None,
+    };
+    let session_envp = unsafe { with_session_env(envp, exec_path.as_deref()) };
+    let envp = session_envp.as_ref().map_or(envp, |(_, ptrs)| ptrs.as_ptr());
+    match resolved {
+        Resolved::Original => unsafe { (get_original_functions().execve)(pathname, argv, envp) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().execve)(overlay_cstr.as_ptr(), argv, envp)
+        },
+        Resolved::Error(errno) => fail(errno),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn execvp(file: *const c_char, argv: *const *const c_char) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().execvp)(file, argv) };
+    }
+    // Names without a slash go through the PATH search and are left alone.
+    let has_slash = unsafe { cstr_to_string(file) }.is_some_and(|f| f.contains('/'));
+    let resolved = if has_slash {
+        unsafe { resolve_path(file) }
+    } else {
+        Resolved::Original
+    };
+    let exec_path = match resolved {
+        Resolved::Redirect(_) => unsafe { exec_target(file) },
+        _ => None,
+    };
+    let environ = caller_environ();
+    let session_envp = unsafe { with_session_env(environ, exec_path.as_deref()) };
+    let envp = session_envp.as_ref().map_or(environ, |(_, ptrs)| ptrs.as_ptr());
+    match resolved {
+        Resolved::Original => unsafe { (get_original_functions().execvpe)(file, argv, envp) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().execvpe)(overlay_cstr.as_ptr(), argv, envp)
+        },
+        Resolved::Error(errno) => fail(errno),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn execv(pathname: *const c_char, argv: *const *const c_char) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().execv)(pathname, argv) };
+    }
+    unsafe { execve(pathname, argv, caller_environ()) }
+}
+
+/// Removes `pathname` for `dirfd` from the merged view, returning the call's result, or `None`
+/// when the call should go straight to the base filesystem.
+unsafe fn remove_path_at(dirfd: c_int, pathname: *const c_char, dir: bool) -> Option<c_int> {
+    let virtual_str = unsafe { virtual_path_at(dirfd, pathname) }?;
+    let path_str = match resolve_overlay_symlinks(&virtual_str, false) {
+        Ok(path_str) => path_str,
+        Err(errno) => return Some(fail(errno)),
+    };
+    if is_read_only(&path_str) {
+        return Some(fail(read_only_errno(&path_str, false)));
+    }
+    let blacklisted = get_blacklist().lock().unwrap().is_blacklisted(&path_str);
+    let base = blacklisted || write_layer(&path_str).is_ok_and(|layer| layer.is_none());
+    if base && (path_str != virtual_str || unsafe { is_relative_to_overlay(dirfd, pathname) }) {
+        // An overlay symlink, or a relative path from inside an overlay, led to a path the base
+        // filesystem answers for.
+        let path_cstr = CString::new(path_str).unwrap();
+        return Some(unsafe {
+            if dir {
+                (get_original_functions().rmdir)(path_cstr.as_ptr())
+            } else {
+                (get_original_functions().unlink)(path_cstr.as_ptr())
+            }
+        });
+    }
+    if blacklisted {
+        return None;
+    }
+    let result = write_layer(&path_str).and_then(|layer| match layer {
+        Some(layer) => remove_entry(&layer, &path_str, dir).map(Some),
+        None => Ok(None),
+    });
+    match result {
+        Ok(Some(())) => Some(0),
+        Ok(None) => None,
+        Err(errno) => Some(fail(errno)),
+    }
+}
+
+/// Checks the `renameat2` `flags` against the merged view, of which the kernel only sees one layer:
+/// `RENAME_NOREPLACE` fails if `new` exists in any layer, and without an upper layer to copy both
+/// entries into, `RENAME_EXCHANGE` fails with `EXDEV` unless they live in the same layer.
+fn check_rename_flags(old: &str, new: &str, flags: c_uint) -> Result<(), c_int> {
+    let known = libc::RENAME_NOREPLACE | libc::RENAME_EXCHANGE | libc::RENAME_WHITEOUT;
+    let exchange = (flags & libc::RENAME_EXCHANGE) != 0;
+    if (flags & !known) != 0 || (exchange && flags != libc::RENAME_EXCHANGE) {
+        return Err(libc::EINVAL);
+    }
+    if (flags & libc::RENAME_NOREPLACE) != 0 && visible_source(new).is_ok() {
+        return Err(libc::EEXIST);
+    }
+    if exchange && write_layer(old)? != write_layer(new)? {
+        return Err(libc::EXDEV);
+    }
+    Ok(())
+}
+
+/// Renames `oldpath` to `newpath` in the merged view under the `renameat2` `flags`, returning the
+/// call's result, or `None` when the call should go straight to the base filesystem.
+unsafe fn rename_path_at(
+    olddirfd: c_int,
+    oldpath: *const c_char,
+    newdirfd: c_int,
+    newpath: *const c_char,
+    flags: c_uint,
+) -> Option<c_int> {
+    let old_virtual = unsafe { virtual_path_at(olddirfd, oldpath) }?;
+    let new_virtual = unsafe { virtual_path_at(newdirfd, newpath) }?;
+    let (oldpath_str, newpath_str) = match (
+        resolve_overlay_symlinks(&old_virtual, false),
+        resolve_overlay_symlinks(&new_virtual, false),
+    ) {
+        (Ok(oldpath_str), Ok(newpath_str)) => (oldpath_str, newpath_str),
+        (Err(errno), _) | (_, Err(errno)) => return Some(fail(errno)),
+    };
+    if is_read_only(&oldpath_str) {
+        return Some(fail(read_only_errno(&oldpath_str, false)));
+    }
+    if is_read_only(&newpath_str) {
+        // The destination may legitimately be missing; only a missing source beats `EROFS`.
+        let errno = visible_source(&oldpath_str).err().unwrap_or(libc::EROFS);
+        return Some(fail(errno));
+    }
+    let blacklisted = {
+        let blacklist = get_blacklist().lock().unwrap();
+        blacklist.is_blacklisted(&oldpath_str) || blacklist.is_blacklisted(&newpath_str)
+    };
+    if !blacklisted && let Err(errno) = check_rename_flags(&oldpath_str, &newpath_str, flags) {
+        return Some(fail(errno));
+    }
+    let base = blacklisted || write_layer(&oldpath_str).is_ok_and(|layer| layer.is_none());
+    let relocated = oldpath_str != old_virtual
+        || newpath_str != new_virtual
+        || unsafe { is_relative_to_overlay(olddirfd, oldpath) }
+        || unsafe { is_relative_to_overlay(newdirfd, newpath) };
+    if base && relocated {
+        // An overlay symlink, or a relative path from inside an overlay, led to a path the base
+        // filesystem answers for.
+        let old_cstr = CString::new(oldpath_str).unwrap();
+        let new_cstr = CString::new(newpath_str).unwrap();
+        return Some(unsafe {
+            (get_original_functions().renameat2)(
+                libc::AT_FDCWD,
+                old_cstr.as_ptr(),
+                libc::AT_FDCWD,
+                new_cstr.as_ptr(),
+                flags,
+            )
+        });
+    }
+    if blacklisted {
+        return None;
+    }
+    let result = write_layer(&oldpath_str).and_then(|layer| match layer {
+        Some(layer) => rename_entry_with_flags(&layer, &oldpath_str, &newpath_str, flags).map(Some),
+        None => Ok(None),
+    });
+    match result {
+        Ok(Some(())) => Some(0),
+        Ok(None) => None,
+        Err(errno) => Some(fail(errno)),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn unlink(pathname: *const c_char) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().unlink)(pathname) };
+    }
+    if let Some(result) = unsafe { remove_path_at(libc::AT_FDCWD, pathname, false) } {
+        return result;
+    }
+    unsafe { (get_original_functions().unlink)(pathname) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn unlinkat(dirfd: c_int, pathname: *const c_char, flags: c_int) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().unlinkat)(dirfd, pathname, flags) };
+    }
+    let dir = (flags & libc::AT_REMOVEDIR) != 0;
+    if let Some(result) = unsafe { remove_path_at(dirfd, pathname, dir) } {
+        return result;
+    }
+    unsafe { (get_original_functions().unlinkat)(dirfd, pathname, flags) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rmdir(pathname: *const c_char) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().rmdir)(pathname) };
+    }
+    if let Some(result) = unsafe { remove_path_at(libc::AT_FDCWD, pathname, true) } {
+        return result;
+    }
+    unsafe { (get_original_functions().rmdir)(pathname) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mkdir(pathname: *const c_char, mode: mode_t) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().mkdir)(pathname, mode) };
+    }
+    match unsafe { resolve_create_path(pathname) } {
+        Resolved::Original => unsafe { (get_original_functions().mkdir)(pathname, mode) },
+        Resolved::Redirect(overlay_cstr) => {
+            let result = unsafe { (get_original_functions().mkdir)(overlay_cstr.as_ptr(), mode) };
+            if result == 0 && get_upper_dir().is_some() {
+                if let Some(path_str) = unsafe { virtual_path_at(libc::AT_FDCWD, pathname) } {
+                    hide_lower_dirs(&path_str);
+                }
+            }
+            result
+        }
+        Resolved::Error(errno) => fail(errno),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mkdirat(dirfd: c_int, pathname: *const c_char, mode: mode_t) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().mkdirat)(dirfd, pathname, mode) };
+    }
+    match unsafe { resolve_create_path_at(dirfd, pathname) } {
+        Resolved::Original => unsafe { (get_original_functions().mkdirat)(dirfd, pathname, mode) },
+        Resolved::Redirect(overlay_cstr) => {
+            let result = unsafe { (get_original_functions().mkdirat)(dirfd, overlay_cstr.as_ptr(), mode) };
+            if result == 0 && get_upper_dir().is_some() {
+                if let Some(path_str) = unsafe { virtual_path_at(dirfd, pathname) } {
+                    hide_lower_dirs(&path_str);
+                }
+            }
+            result
+        }
+        Resolved::Error(errno) => fail(errno),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rename(oldpath: *const c_char, newpath: *const c_char) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().rename)(oldpath, newpath) };
+    }
+    if let Some(result) =
+        unsafe { rename_path_at(libc::AT_FDCWD, oldpath, libc::AT_FDCWD, newpath, 0) }
+    {
+        return result;
+    }
+    unsafe { (get_original_functions().rename)(oldpath, newpath) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn renameat(
+    olddirfd: c_int,
+    oldpath: *const c_char,
+    newdirfd: c_int,
+    newpath: *const c_char,
+) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().renameat)(olddirfd, oldpath, newdirfd, newpath) };
+    }
+    if let Some(result) = unsafe { rename_path_at(olddirfd, oldpath, newdirfd, newpath, 0) } {
+        return result;
+    }
+    unsafe { (get_original_functions().renameat)(olddirfd, oldpath, newdirfd, newpath) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn renameat2(
+    olddirfd: c_int,
+    oldpath: *const c_char,
+    newdirfd: c_int,
+    newpath: *const c_char,
+    flags: c_uint,
+) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe {
+            (get_original_functions().renameat2)(olddirfd, oldpath, newdirfd, newpath, flags)
+        };
+    }
+    if let Some(result) = unsafe { rename_path_at(olddirfd, oldpath, newdirfd, newpath, flags) } {
+        return result;
+    }
+    unsafe { (get_original_functions().renameat2)(olddirfd, oldpath, newdirfd, newpath, flags) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn creat(pathname: *const c_char, mode: mode_t) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().creat)(pathname, mode) };
+    }
+    let resolved = unsafe { resolve_open_path_at(libc::AT_FDCWD, pathname, libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC) };
+    let fd = unsafe { creat_resolved(pathname, &resolved, mode) };
+    unsafe { track_fd(fd, libc::AT_FDCWD, pathname, &resolved) }
+}
+
+unsafe fn creat_resolved(pathname: *const c_char, resolved: &Resolved, mode: mode_t) -> c_int {
+    match resolved {
+        Resolved::Original => unsafe { (get_original_functions().creat)(pathname, mode) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().creat)(overlay_cstr.as_ptr(), mode)
+        },
+        Resolved::Error(errno) => fail(*errno),
+    }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn open64(pathname: *const c_char, flags: c_int, mode: mode_t) -> c_int {
+pub unsafe extern "C" fn creat64(pathname: *const c_char, mode: mode_t) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().open64)(pathname, flags, mode) };
+        return unsafe { (get_original_functions().creat64)(pathname, mode) };
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().open64)(pathname, flags, mode) };
-        }
+    let resolved = unsafe { resolve_open_path_at(libc::AT_FDCWD, pathname, libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC) };
+    let fd = unsafe { creat64_resolved(pathname, &resolved, mode) };
+    unsafe { track_fd(fd, libc::AT_FDCWD, pathname, &resolved) }
+}
+
+unsafe fn creat64_resolved(pathname: *const c_char, resolved: &Resolved, mode: mode_t) -> c_int {
+    match resolved {
+        Resolved::Original => unsafe { (get_original_functions().creat64)(pathname, mode) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().creat64)(overlay_cstr.as_ptr(), mode)
+        },
+        Resolved::Error(errno) => fail(*errno),
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe {
-                (get_original_functions().open64)(overlay_cstr.as_ptr(), flags, mode)
-            };
-        }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn chdir(path: *const c_char) -> c_int {
+    let result = unsafe { chdir_resolved(path) };
+    invalidate_current_dir();
+    result
+}
+
+unsafe fn chdir_resolved(path: *const c_char) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().chdir)(path) };
+    }
+    // Change directory by absolute virtual path. A relative one would be taken against the
+    // kernel's directory, which may be inside an overlay, so `..` would leave the merged view.
+    let absolute = unsafe { virtual_path(path) }.and_then(|path_str| CString::new(path_str).ok());
+    let path = absolute.as_ref().map_or(path, |path_cstr| path_cstr.as_ptr());
+    match unsafe { resolve_path(path) } {
+        Resolved::Original => unsafe { (get_original_functions().chdir)(path) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().chdir)(overlay_cstr.as_ptr())
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().open64)(pathname, flags, mode) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn openat(
-    dirfd: c_int,
-    pathname: *const c_char,
-    flags: c_int,
-    mode: mode_t,
-) -> c_int {
+pub unsafe extern "C" fn fchdir(fd: c_int) -> c_int {
+    let result = unsafe { (get_original_functions().fchdir)(fd) };
+    invalidate_current_dir();
+    result
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn getcwd(buf: *mut c_char, size: size_t) -> *mut c_char {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().openat)(dirfd, pathname, flags, mode) };
+        return unsafe { (get_original_functions().getcwd)(buf, size) };
     }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-                return unsafe { (get_original_functions().openat)(dirfd, pathname, flags, mode) };
-            }
+    match current_dir() {
+        Some(cwd) => unsafe { copy_path_out(&cwd, buf, size) },
+        None => unsafe { (get_original_functions().getcwd)(buf, size) },
+    }
+}
+
+/// Like glibc's, returns `$PWD` if it names the working directory, so a path reached through a
+/// symlink is kept, and the virtual working directory otherwise. The caller frees the result.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_current_dir_name() -> *mut c_char {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().get_current_dir_name)() };
+    }
+    if let Some(pwd) = env::var_os("PWD").and_then(|pwd| CString::new(pwd.into_encoded_bytes()).ok())
+        && pwd.as_bytes().starts_with(b"/")
+    {
+        let mut pwd_st: libc::stat = unsafe { std::mem::zeroed() };
+        let mut dot_st: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { stat(pwd.as_ptr(), &mut pwd_st) } == 0
+            && unsafe { stat(c".".as_ptr(), &mut dot_st) } == 0
+            && (pwd_st.st_dev, pwd_st.st_ino) == (dot_st.st_dev, dot_st.st_ino)
+        {
+            return unsafe { libc::strdup(pwd.as_ptr()) };
         }
     }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if let Some(overlay_path) = find_overlay_path(&path_str) {
-                let overlay_cstr = CString::new(overlay_path).unwrap();
-                return unsafe {
-                    (get_original_functions().openat)(dirfd, overlay_cstr.as_ptr(), flags, mode)
-                };
+    unsafe { getcwd(std::ptr::null_mut(), 0) }
+}
+
+/// Canonicalizes `path` in the merged view, like `realpath`: makes it absolute against the
+/// virtual working directory and expands every symlink, in whichever layer it lives, resolving
+/// each target in the merged view again. Every component must exist.
+fn canonicalize_virtual(path: &str) -> Result<String, c_int> {
+    if path.is_empty() {
+        return Err(libc::ENOENT);
+    }
+    // Components still to walk, next one last.
+    let mut pending: Vec<String> = path.split('/').rev().map(String::from).collect();
+    if !path.starts_with('/') {
+        let cwd = current_dir().ok_or(libc::ENOENT)?;
+        pending.extend(cwd.split('/').rev().map(String::from));
+    }
+    let mut resolved: Vec<String> = Vec::new();
+    let mut symlinks_expanded = 0;
+    while let Some(component) = pending.pop() {
+        match component.as_str() {
+            "" | "." => continue,
+            ".." => {
+                resolved.pop();
+                continue;
+            }
+            _ => {}
+        }
+        let candidate = join_path(&format!("/{}", resolved.join("/")), &component);
+        let source = if get_blacklist().lock().unwrap().is_blacklisted(&candidate) {
+            candidate
+        } else {
+            visible_source(&candidate)?
+        };
+        let st = lstat_physical(&source).ok_or(libc::ENOENT)?;
+        match st.st_mode & libc::S_IFMT {
+            libc::S_IFLNK => {
+                if symlinks_expanded == MAX_SYMLINK_DEPTH {
+                    return Err(libc::ELOOP);
+                }
+                symlinks_expanded += 1;
+                let target = read_symlink(&source).ok_or(libc::ENOENT)?;
+                if target.starts_with('/') {
+                    resolved.clear();
+                }
+                pending.extend(target.split('/').rev().map(String::from));
             }
+            libc::S_IFDIR => resolved.push(component),
+            _ if !pending.is_empty() => return Err(libc::ENOTDIR),
+            _ => resolved.push(component),
         }
     }
-    unsafe { (get_original_functions().openat)(dirfd, pathname, flags, mode) }
+    Ok(format!("/{}", resolved.join("/")))
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn openat64(
-    dirfd: c_int,
-    pathname: *const c_char,
-    flags: c_int,
-    mode: mode_t,
-) -> c_int {
+pub unsafe extern "C" fn realpath(path: *const c_char, resolved_path: *mut c_char) -> *mut c_char {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().openat64)(dirfd, pathname, flags, mode) };
+        return unsafe { (get_original_functions().realpath)(path, resolved_path) };
     }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-                return unsafe { (get_original_functions().openat64)(dirfd, pathname, flags, mode) };
-            }
-        }
+    if path.is_null() {
+        set_errno(libc::EINVAL);
+        return std::ptr::null_mut();
     }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if let Some(overlay_path) = find_overlay_path(&path_str) {
-                let overlay_cstr = CString::new(overlay_path).unwrap();
-                return unsafe {
-                    (get_original_functions().openat64)(dirfd, overlay_cstr.as_ptr(), flags, mode)
-                };
-            }
+    let Some(path_str) = (unsafe { cstr_to_string(path) }) else {
+        return unsafe { (get_original_functions().realpath)(path, resolved_path) };
+    };
+    match canonicalize_virtual(&path_str) {
+        Ok(canonical) if !resolved_path.is_null() && canonical.len() >= libc::PATH_MAX as usize => {
+            set_errno(libc::ENAMETOOLONG);
+            std::ptr::null_mut()
+        }
+        Ok(canonical) if !resolved_path.is_null() => unsafe {
+            copy_path_out(&canonical, resolved_path, libc::PATH_MAX as size_t)
+        },
+        Ok(canonical) => unsafe { copy_path_out(&canonical, std::ptr::null_mut(), 0) },
+        Err(errno) => {
+            set_errno(errno);
+            std::ptr::null_mut()
         }
     }
-    unsafe { (get_original_functions().openat64)(dirfd, pathname, flags, mode) }
 }
 
+/// Fortified `realpath`; an undersized buffer is left to glibc to report.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn fopen(pathname: *const c_char, mode: *const c_char) -> *mut FILE {
+pub unsafe extern "C" fn __realpath_chk(
+    path: *const c_char,
+    resolved_path: *mut c_char,
+    resolved_len: size_t,
+) -> *mut c_char {
+    if INIT_GUARD.load(Ordering::Relaxed)
+        || (!resolved_path.is_null() && resolved_len < libc::PATH_MAX as size_t)
+    {
+        return unsafe { (get_original_functions().realpath_chk)(path, resolved_path, resolved_len) };
+    }
+    unsafe { realpath(path, resolved_path) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn canonicalize_file_name(path: *const c_char) -> *mut c_char {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().fopen)(pathname, mode) };
+        return unsafe { (get_original_functions().canonicalize_file_name)(path) };
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().fopen)(pathname, mode) };
-        }
+    unsafe { realpath(path, std::ptr::null_mut()) }
+}
+
+/// Returns `path` the way `getcwd` does: copied into `buf` if it fits in `size` bytes, or into a
+/// newly `malloc`ed buffer of `size` bytes (or just large enough, if `size` is 0) if `buf` is null.
+unsafe fn copy_path_out(path: &str, buf: *mut c_char, size: size_t) -> *mut c_char {
+    let needed = path.len() + 1;
+    if !buf.is_null() && size == 0 {
+        set_errno(libc::EINVAL);
+        return std::ptr::null_mut();
+    }
+    if size != 0 && size < needed {
+        set_errno(libc::ERANGE);
+        return std::ptr::null_mut();
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe { (get_original_functions().fopen)(overlay_cstr.as_ptr(), mode) };
+    let out = if buf.is_null() {
+        let out = unsafe { libc::malloc(needed.max(size)) } as *mut c_char;
+        if out.is_null() {
+            set_errno(libc::ENOMEM);
+            return out;
         }
+        out
+    } else {
+        buf
+    };
+    unsafe {
+        std::ptr::copy_nonoverlapping(path.as_ptr() as *const c_char, out, path.len());
+        *out.add(path.len()) = 0;
     }
-    unsafe { (get_original_functions().fopen)(pathname, mode) }
+    out
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn fopen64(pathname: *const c_char, mode: *const c_char) -> *mut FILE {
+pub unsafe extern "C" fn chmod(pathname: *const c_char, mode: mode_t) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().fopen64)(pathname, mode) };
+        return unsafe { (get_original_functions().chmod)(pathname, mode) };
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().fopen64)(pathname, mode) };
-        }
+    match unsafe { resolve_write_path(pathname, true) } {
+        Resolved::Original => unsafe { (get_original_functions().chmod)(pathname, mode) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().chmod)(overlay_cstr.as_ptr(), mode)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe { (get_original_functions().fopen64)(overlay_cstr.as_ptr(), mode) };
-        }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fchmodat(
+    dirfd: c_int,
+    pathname: *const c_char,
+    mode: mode_t,
+    flags: c_int,
+) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().fchmodat)(dirfd, pathname, mode, flags) };
+    }
+    let follow = (flags & libc::AT_SYMLINK_NOFOLLOW) == 0;
+    match unsafe { resolve_write_path_at(dirfd, pathname, follow) } {
+        Resolved::Original => unsafe {
+            (get_original_functions().fchmodat)(dirfd, pathname, mode, flags)
+        },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().fchmodat)(dirfd, overlay_cstr.as_ptr(), mode, flags)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().fopen64)(pathname, mode) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn stat(pathname: *const c_char, statbuf: *mut libc::stat) -> c_int {
+pub unsafe extern "C" fn chown(pathname: *const c_char, owner: uid_t, group: gid_t) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        let original_functions = get_original_functions();
-        return unsafe { (original_functions.stat)(pathname, statbuf) };
+        return unsafe { (get_original_functions().chown)(pathname, owner, group) };
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().stat)(pathname, statbuf) };
-        }
+    match unsafe { resolve_write_path(pathname, true) } {
+        Resolved::Original => unsafe { (get_original_functions().chown)(pathname, owner, group) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().chown)(overlay_cstr.as_ptr(), owner, group)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    if OVERLAY_DISABLED.with(|disabled| *disabled.borrow()) {
-        let original_functions = get_original_functions();
-        return unsafe { (original_functions.stat)(pathname, statbuf) };
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fchownat(
+    dirfd: c_int,
+    pathname: *const c_char,
+    owner: uid_t,
+    group: gid_t,
+    flags: c_int,
+) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe {
+            (get_original_functions().fchownat)(
+                dirfd,
+                pathname,
+                owner,
+                group,
+                flags,
+            )
+        };
     }
-    // First, try original stat
-    let original_result = unsafe { (get_original_functions().stat)(pathname, statbuf) };
-    if original_result == 0 {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            let mode = unsafe { (*statbuf).st_mode };
-            if (mode & libc::S_IFMT) == libc::S_IFREG {
-                if let Some(overlay_path) = find_overlay_path(&path_str) {
-                    let overlay_cstr = CString::new(overlay_path).unwrap();
-                    return unsafe { (get_original_functions().stat)(overlay_cstr.as_ptr(), statbuf) };
-                }
-            } else if (mode & libc::S_IFMT) == libc::S_IFDIR {
-                if let Some(overlay_path) = find_overlay_path(&path_str).or_else(|| find_overlay_dir(&path_str)) {
-                    let overlay_cstr = CString::new(overlay_path).unwrap();
-                    return unsafe { (get_original_functions().stat)(overlay_cstr.as_ptr(), statbuf) };
-                }
-            }
-        }
-        return 0;
-    } else {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if let Some(overlay_path) = find_overlay_path(&path_str).or_else(|| find_overlay_dir(&path_str)) {
-                let overlay_cstr = CString::new(overlay_path).unwrap();
-                return unsafe { (get_original_functions().stat)(overlay_cstr.as_ptr(), statbuf) };
-            }
-        }
-        return original_result;
+    let follow = (flags & libc::AT_SYMLINK_NOFOLLOW) == 0;
+    match unsafe { resolve_write_path_at(dirfd, pathname, follow) } {
+        Resolved::Original => unsafe {
+            (get_original_functions().fchownat)(dirfd, pathname, owner, group, flags)
+        },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().fchownat)(dirfd, overlay_cstr.as_ptr(), owner, group, flags)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn lstat(pathname: *const c_char, statbuf: *mut libc::stat) -> c_int {
+pub unsafe extern "C" fn lchown(pathname: *const c_char, owner: uid_t, group: gid_t) -> c_int {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().lchown)(pathname, owner, group) };
+    }
+    match unsafe { resolve_write_path(pathname, false) } {
+        Resolved::Original => unsafe { (get_original_functions().lchown)(pathname, owner, group) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().lchown)(overlay_cstr.as_ptr(), owner, group)
+        },
+        Resolved::Error(errno) => fail(errno),
+    }
+}
+
+/// Hard-links within the upper layer. The existing file is copied up first so the new name never
+/// shares an inode with a lower layer.
+unsafe fn upper_linkat(
+    olddirfd: c_int,
+    oldpath: *const c_char,
+    newdirfd: c_int,
+    newpath: *const c_char,
+    flags: c_int,
+) -> c_int {
+    let follow = (flags & libc::AT_SYMLINK_FOLLOW) != 0;
+    let upper_old = match unsafe { resolve_write_path_at(olddirfd, oldpath, follow) } {
+        Resolved::Original => None,
+        Resolved::Redirect(upper_cstr) => Some(upper_cstr),
+        Resolved::Error(errno) => return fail(errno),
+    };
+    let upper_new = match unsafe { resolve_create_path_at(newdirfd, newpath) } {
+        Resolved::Original => None,
+        Resolved::Redirect(upper_cstr) => Some(upper_cstr),
+        Resolved::Error(errno) => return fail(errno),
+    };
+    let (olddirfd, oldpath) = upper_old
+        .as_ref()
+        .map_or((olddirfd, oldpath), |c| (libc::AT_FDCWD, c.as_ptr()));
+    let (newdirfd, newpath) = upper_new
+        .as_ref()
+        .map_or((newdirfd, newpath), |c| (libc::AT_FDCWD, c.as_ptr()));
+    unsafe { (get_original_functions().linkat)(olddirfd, oldpath, newdirfd, newpath, flags) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn link(oldpath: *const c_char, newpath: *const c_char) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        let original_functions = get_original_functions();
-        return unsafe { (original_functions.lstat)(pathname, statbuf) };
+        return unsafe { (get_original_functions().link)(oldpath, newpath) };
+    }
+    // The base filesystem gets absolute paths where the working directory lies inside an overlay.
+    let base_link = || unsafe {
+        let old_cstr = base_path_at(libc::AT_FDCWD, oldpath);
+        let new_cstr = base_path_at(libc::AT_FDCWD, newpath);
+        (get_original_functions().link)(
+            old_cstr.as_ref().map_or(oldpath, |c| c.as_ptr()),
+            new_cstr.as_ref().map_or(newpath, |c| c.as_ptr()),
+        )
+    };
+    if let Some(path_str) = unsafe { virtual_path(newpath) }
+        && is_read_only(&path_str)
+    {
+        return fail(read_only_errno(&path_str, true));
+    }
+    if let Some(oldpath_str) = unsafe { virtual_path(oldpath) } {
+        if get_blacklist().lock().unwrap().is_blacklisted(&oldpath_str) {
+            return base_link();
+        }
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().lstat)(pathname, statbuf) };
+    if let Some(newpath_str) = unsafe { virtual_path(newpath) } {
+        if get_blacklist().lock().unwrap().is_blacklisted(&newpath_str) {
+            return base_link();
         }
     }
-    if OVERLAY_DISABLED.with(|disabled| *disabled.borrow()) {
-        let original_functions = get_original_functions();
-        return unsafe { (original_functions.lstat)(pathname, statbuf) };
+    if get_upper_dir().is_some() {
+        return unsafe { upper_linkat(libc::AT_FDCWD, oldpath, libc::AT_FDCWD, newpath, 0) };
     }
-    // First, try original lstat
-    let original_result = unsafe { (get_original_functions().lstat)(pathname, statbuf) };
-    if original_result == 0 {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            let mode = unsafe { (*statbuf).st_mode };
-            if (mode & libc::S_IFMT) == libc::S_IFREG {
-                if let Some(overlay_path) = find_overlay_path(&path_str) {
-                    let overlay_cstr = CString::new(overlay_path).unwrap();
-                    return unsafe { (get_original_functions().lstat)(overlay_cstr.as_ptr(), statbuf) };
-                }
-            } else if (mode & libc::S_IFMT) == libc::S_IFDIR {
-                if let Some(overlay_path) = find_overlay_path(&path_str).or_else(|| find_overlay_dir(&path_str)) {
-                    let overlay_cstr = CString::new(overlay_path).unwrap();
-                    return unsafe { (get_original_functions().lstat)(overlay_cstr.as_ptr(), statbuf) };
-                }
-            }
+    if let Some(oldpath_str) = unsafe { virtual_path(oldpath) } {
+        if is_hidden(&oldpath_str) {
+            return fail(libc::ENOENT);
         }
-        return 0;
-    } else {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if let Some(overlay_path) = find_overlay_path(&path_str).or_else(|| find_overlay_dir(&path_str)) {
-                let overlay_cstr = CString::new(overlay_path).unwrap();
-                return unsafe { (get_original_functions().lstat)(overlay_cstr.as_ptr(), statbuf) };
+    }
+    if let Some(oldpath_str) = unsafe { virtual_path(oldpath) } {
+        if let OverlayLookup::Found(overlay_oldpath) = find_overlay_path(&oldpath_str) {
+            let overlay_old_cstr = CString::new(overlay_oldpath).unwrap();
+            if let Some(newpath_str) = unsafe { virtual_path(newpath) } {
+                if let OverlayLookup::Found(overlay_newpath) = find_overlay_path(&newpath_str) {
+                    let overlay_new_cstr = CString::new(overlay_newpath).unwrap();
+                    return unsafe {
+                        (get_original_functions().link)(
+                            overlay_old_cstr.as_ptr(),
+                            overlay_new_cstr.as_ptr(),
+                        )
+                    };
+                }
             }
         }
-        return original_result;
     }
+    base_link()
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn stat64(pathname: *const c_char, statbuf: *mut libc::stat64) -> c_int {
+pub unsafe extern "C" fn linkat(
+    olddirfd: c_int,
+    oldpath: *const c_char,
+    newdirfd: c_int,
+    newpath: *const c_char,
+    flags: c_int,
+) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        let original_functions = get_original_functions();
-        return unsafe { (original_functions.stat64)(pathname, statbuf) };
+        return unsafe { (get_original_functions().linkat)(olddirfd, oldpath, newdirfd, newpath, flags) };
+    }
+    // The base filesystem gets absolute paths where a dirfd lies inside an overlay.
+    let base_linkat = || unsafe {
+        let old_cstr = base_path_at(olddirfd, oldpath);
+        let new_cstr = base_path_at(newdirfd, newpath);
+        (get_original_functions().linkat)(
+            olddirfd,
+            old_cstr.as_ref().map_or(oldpath, |c| c.as_ptr()),
+            newdirfd,
+            new_cstr.as_ref().map_or(newpath, |c| c.as_ptr()),
+            flags,
+        )
+    };
+    if let Some(path_str) = unsafe { virtual_path_at(newdirfd, newpath) }
+        && is_read_only(&path_str)
+    {
+        return fail(read_only_errno(&path_str, true));
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().stat64)(pathname, statbuf) };
+    if let Some(oldpath_str) = unsafe { virtual_path_at(olddirfd, oldpath) } {
+        if get_blacklist().lock().unwrap().is_blacklisted(&oldpath_str) {
+            return base_linkat();
         }
     }
-    if OVERLAY_DISABLED.with(|disabled| *disabled.borrow()) {
-        let original_functions = get_original_functions();
-        return unsafe { (original_functions.stat64)(pathname, statbuf) };
+    if let Some(newpath_str) = unsafe { virtual_path_at(newdirfd, newpath) } {
+        if get_blacklist().lock().unwrap().is_blacklisted(&newpath_str) {
+            return base_linkat();
+        }
     }
-    // First, try original stat64
-    let original_result = unsafe { (get_original_functions().stat64)(pathname, statbuf) };
-    if original_result == 0 {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            let mode = unsafe { (*statbuf).st_mode };
-            if (mode & libc::S_IFMT) == libc::S_IFREG {
-                if let Some(overlay_path) = find_overlay_path(&path_str) {
-                    let overlay_cstr = CString::new(overlay_path).unwrap();
-                    return unsafe { (get_original_functions().stat64)(overlay_cstr.as_ptr(), statbuf) };
-                }
-            } else if (mode & libc::S_IFMT) == libc::S_IFDIR {
-                if let Some(overlay_path) = find_overlay_path(&path_str).or_else(|| find_overlay_dir(&path_str)) {
-                    let overlay_cstr = CString::new(overlay_path).unwrap();
-                    return unsafe { (get_original_functions().stat64)(overlay_cstr.as_ptr(), statbuf) };
-                }
-            }
+    if get_upper_dir().is_some() {
+        return unsafe { upper_linkat(olddirfd, oldpath, newdirfd, newpath, flags) };
+    }
+    if let Some(oldpath_str) = unsafe { virtual_path_at(olddirfd, oldpath) } {
+        if is_hidden(&oldpath_str) {
+            return fail(libc::ENOENT);
         }
-        return 0;
-    } else {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if let Some(overlay_path) = find_overlay_path(&path_str).or_else(|| find_overlay_dir(&path_str)) {
-                let overlay_cstr = CString::new(overlay_path).unwrap();
-                return unsafe { (get_original_functions().stat64)(overlay_cstr.as_ptr(), statbuf) };
+    }
+    if let Some(oldpath_str) = unsafe { virtual_path_at(olddirfd, oldpath) } {
+        if let OverlayLookup::Found(overlay_oldpath) = find_overlay_path(&oldpath_str) {
+            let overlay_old_cstr = CString::new(overlay_oldpath).unwrap();
+            if let Some(newpath_str) = unsafe { virtual_path_at(newdirfd, newpath) } {
+                if let OverlayLookup::Found(overlay_newpath) = find_overlay_path(&newpath_str) {
+                    let overlay_new_cstr = CString::new(overlay_newpath).unwrap();
+                    return unsafe {
+                        (get_original_functions().linkat)(
+                            olddirfd,
+                            overlay_old_cstr.as_ptr(),
+                            newdirfd,
+                            overlay_new_cstr.as_ptr(),
+                            flags,
+                        )
+                    };
+                }
             }
         }
-        return original_result;
     }
+    base_linkat()
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn lstat64(pathname: *const c_char, statbuf: *mut libc::stat64) -> c_int {
+pub unsafe extern "C" fn symlink(target: *const c_char, linkpath: *const c_char) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        let original_functions = get_original_functions();
-        return unsafe { (original_functions.lstat64)(pathname, statbuf) };
+        return unsafe { (get_original_functions().symlink)(target, linkpath) };
+    }
+    // The base filesystem gets an absolute link path where the working directory lies inside an
+    // overlay; the target is stored as given.
+    let base_symlink = || unsafe {
+        let link_cstr = base_path_at(libc::AT_FDCWD, linkpath);
+        (get_original_functions().symlink)(
+            target,
+            link_cstr.as_ref().map_or(linkpath, |c| c.as_ptr()),
+        )
+    };
+    if let Some(path_str) = unsafe { virtual_path(linkpath) }
+        && is_read_only(&path_str)
+    {
+        return fail(read_only_errno(&path_str, true));
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().lstat64)(pathname, statbuf) };
+    if let Some(target_str) = unsafe { cstr_to_string(target) } {
+        if get_blacklist().lock().unwrap().is_blacklisted(&target_str) {
+            return base_symlink();
         }
     }
-    if OVERLAY_DISABLED.with(|disabled| *disabled.borrow()) {
-        let original_functions = get_original_functions();
-        return unsafe { (original_functions.lstat64)(pathname, statbuf) };
+    if let Some(linkpath_str) = unsafe { virtual_path(linkpath) } {
+        if get_blacklist().lock().unwrap().is_blacklisted(&linkpath_str) {
+            return base_symlink();
+        }
     }
-    // First, try original lstat64
-    let original_result = unsafe { (get_original_functions().lstat64)(pathname, statbuf) };
-    if original_result == 0 {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            let mode = unsafe { (*statbuf).st_mode };
-            if (mode & libc::S_IFMT) == libc::S_IFREG {
-                if let Some(overlay_path) = find_overlay_path(&path_str) {
-                    let overlay_cstr = CString::new(overlay_path).unwrap();
-                    return unsafe { (get_original_functions().lstat64)(overlay_cstr.as_ptr(), statbuf) };
-                }
-            } else if (mode & libc::S_IFMT) == libc::S_IFDIR {
-                if let Some(overlay_path) = find_overlay_path(&path_str).or_else(|| find_overlay_dir(&path_str)) {
-                    let overlay_cstr = CString::new(overlay_path).unwrap();
-                    return unsafe { (get_original_functions().lstat64)(overlay_cstr.as_ptr(), statbuf) };
+    if get_upper_dir().is_some() {
+        return match unsafe { resolve_create_path_at(libc::AT_FDCWD, linkpath) } {
+            Resolved::Original => unsafe { (get_original_functions().symlink)(target, linkpath) },
+            Resolved::Redirect(upper_cstr) => unsafe {
+                (get_original_functions().symlink)(target, upper_cstr.as_ptr())
+            },
+            Resolved::Error(errno) => fail(errno),
+        };
+    }
+    if let Some(target_str) = unsafe { cstr_to_string(target) } {
+        if let OverlayLookup::Found(overlay_target) = find_overlay_path(&target_str) {
+            let overlay_target_cstr = CString::new(overlay_target).unwrap();
+            if let Some(linkpath_str) = unsafe { virtual_path(linkpath) } {
+                if let OverlayLookup::Found(overlay_linkpath) = find_overlay_path(&linkpath_str) {
+                    let overlay_link_cstr = CString::new(overlay_linkpath).unwrap();
+                    return unsafe {
+                        (get_original_functions().symlink)(
+                            overlay_target_cstr.as_ptr(),
+                            overlay_link_cstr.as_ptr(),
+                        )
+                    };
                 }
             }
         }
-        return 0;
-    } else {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if let Some(overlay_path) = find_overlay_path(&path_str).or_else(|| find_overlay_dir(&path_str)) {
-                let overlay_cstr = CString::new(overlay_path).unwrap();
-                return unsafe { (get_original_functions().lstat64)(overlay_cstr.as_ptr(), statbuf) };
-            }
-        }
-        return original_result;
     }
+    base_symlink()
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn fstatat(
-    dirfd: c_int,
-    pathname: *const c_char,
-    statbuf: *mut libc::stat,
-    flags: c_int,
+pub unsafe extern "C" fn symlinkat(
+    target: *const c_char,
+    newdirfd: c_int,
+    linkpath: *const c_char,
 ) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().fstatat)(dirfd, pathname, statbuf, flags) };
+        return unsafe { (get_original_functions().symlinkat)(target, newdirfd, linkpath) };
     }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-                return unsafe { (get_original_functions().fstatat)(dirfd, pathname, statbuf, flags) };
-            }
+    // The base filesystem gets an absolute link path where the dirfd lies inside an overlay; the
+    // target is stored as given.
+    let base_symlinkat = || unsafe {
+        let link_cstr = base_path_at(newdirfd, linkpath);
+        (get_original_functions().symlinkat)(
+            target,
+            newdirfd,
+            link_cstr.as_ref().map_or(linkpath, |c| c.as_ptr()),
+        )
+    };
+    if let Some(path_str) = unsafe { virtual_path_at(newdirfd, linkpath) }
+        && is_read_only(&path_str)
+    {
+        return fail(read_only_errno(&path_str, true));
+    }
+    if let Some(target_str) = unsafe { cstr_to_string(target) } {
+        if get_blacklist().lock().unwrap().is_blacklisted(&target_str) {
+            return base_symlinkat();
         }
     }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if let Some(overlay_path) = find_overlay_path(&path_str) {
-                let overlay_cstr = CString::new(overlay_path).unwrap();
-                return unsafe {
-                    (get_original_functions().fstatat)(dirfd, overlay_cstr.as_ptr(), statbuf, flags)
-                };
+    if let Some(linkpath_str) = unsafe { virtual_path_at(newdirfd, linkpath) } {
+        if get_blacklist().lock().unwrap().is_blacklisted(&linkpath_str) {
+            return base_symlinkat();
+        }
+    }
+    if get_upper_dir().is_some() {
+        return match unsafe { resolve_create_path_at(newdirfd, linkpath) } {
+            Resolved::Original => unsafe { (get_original_functions().symlinkat)(target, newdirfd, linkpath) },
+            Resolved::Redirect(upper_cstr) => unsafe {
+                (get_original_functions().symlink)(target, upper_cstr.as_ptr())
+            },
+            Resolved::Error(errno) => fail(errno),
+        };
+    }
+    if let Some(target_str) = unsafe { cstr_to_string(target) } {
+        if let OverlayLookup::Found(overlay_target) = find_overlay_path(&target_str) {
+            let overlay_target_cstr = CString::new(overlay_target).unwrap();
+            if let Some(linkpath_str) = unsafe { virtual_path_at(newdirfd, linkpath) } {
+                if let OverlayLookup::Found(overlay_linkpath) = find_overlay_path(&linkpath_str) {
+                    let overlay_link_cstr = CString::new(overlay_linkpath).unwrap();
+                    return unsafe {
+                        (get_original_functions().symlinkat)(
+                            overlay_target_cstr.as_ptr(),
+                            newdirfd,
+                            overlay_link_cstr.as_ptr(),
+                        )
+                    };
+                }
             }
         }
     }
-    unsafe { (get_original_functions().fstatat)(dirfd, pathname, statbuf, flags) }
+    base_symlinkat()
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn access(pathname: *const c_char, mode: c_int) -> c_int {
+pub unsafe extern "C" fn mknod(pathname: *const c_char, mode: mode_t, dev: libc::dev_t) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().access)(pathname, mode) };
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().access)(pathname, mode) };
-        }
+        return unsafe { (get_original_functions().mknod)(pathname, mode, dev) };
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe { (get_original_functions().access)(overlay_cstr.as_ptr(), mode) };
-        }
+    match unsafe { resolve_create_path(pathname) } {
+        Resolved::Original => unsafe { (get_original_functions().mknod)(pathname, mode, dev) },
+        Resolved::Redirect(upper_cstr) => unsafe {
+            (get_original_functions().mknod)(upper_cstr.as_ptr(), mode, dev)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().access)(pathname, mode) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn faccessat(
+pub unsafe extern "C" fn mknodat(
     dirfd: c_int,
     pathname: *const c_char,
-    mode: c_int,
-    flags: c_int,
+    mode: mode_t,
+    dev: libc::dev_t,
 ) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().faccessat)(dirfd, pathname, mode, flags) };
-    }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-                return unsafe { (get_original_functions().faccessat)(dirfd, pathname, mode, flags) };
-            }
-        }
+        return unsafe { (get_original_functions().mknodat)(dirfd, pathname, mode, dev) };
     }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if let Some(overlay_path) = find_overlay_path(&path_str) {
-                let overlay_cstr = CString::new(overlay_path).unwrap();
-                return unsafe {
-                    (get_original_functions().faccessat)(dirfd, overlay_cstr.as_ptr(), mode, flags)
-                };
-            }
-        }
+    match unsafe { resolve_create_path_at(dirfd, pathname) } {
+        Resolved::Original => unsafe {
+            (get_original_functions().mknodat)(dirfd, pathname, mode, dev)
+        },
+        Resolved::Redirect(upper_cstr) => unsafe {
+            (get_original_functions().mknodat)(dirfd, upper_cstr.as_ptr(), mode, dev)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().faccessat)(dirfd, pathname, mode, flags) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn readlink(
+pub unsafe extern "C" fn __xmknod(
+    _ver: c_int,
     pathname: *const c_char,
-    buf: *mut c_char,
-    bufsiz: size_t,
-) -> ssize_t {
-    if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().readlink)(pathname, buf, bufsiz) };
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().readlink)(pathname, buf, bufsiz) };
-        }
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe {
-                (get_original_functions().readlink)(overlay_cstr.as_ptr(), buf, bufsiz)
-            };
-        }
-    }
-    unsafe { (get_original_functions().readlink)(pathname, buf, bufsiz) }
+    mode: mode_t,
+    dev: *const libc::dev_t,
+) -> c_int {
+    let dev = if dev.is_null() { 0 } else { unsafe { *dev } };
+    unsafe { mknod(pathname, mode, dev) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn readlinkat(
+pub unsafe extern "C" fn __xmknodat(
+    _ver: c_int,
     dirfd: c_int,
     pathname: *const c_char,
-    buf: *mut c_char,
-    bufsiz: size_t,
-) -> ssize_t {
-    if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().readlinkat)(dirfd, pathname, buf, bufsiz) };
-    }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-                return unsafe { (get_original_functions().readlinkat)(dirfd, pathname, buf, bufsiz) };
-            }
-        }
-    }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if let Some(overlay_path) = find_overlay_path(&path_str) {
-                let overlay_cstr = CString::new(overlay_path).unwrap();
-                return unsafe {
-                    (get_original_functions().readlinkat)(dirfd, overlay_cstr.as_ptr(), buf, bufsiz)
-                };
-            }
-        }
-    }
-    unsafe { (get_original_functions().readlinkat)(dirfd, pathname, buf, bufsiz) }
+    mode: mode_t,
+    dev: *const libc::dev_t,
+) -> c_int {
+    let dev = if dev.is_null() { 0 } else { unsafe { *dev } };
+    unsafe { mknodat(dirfd, pathname, mode, dev) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn execve(
-    pathname: *const c_char,
-    argv: *const *const c_char,
-    envp: *const *const c_char,
-) -> c_int {
+pub unsafe extern "C" fn mkfifo(pathname: *const c_char, mode: mode_t) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().execve)(pathname, argv, envp) };
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().execve)(pathname, argv, envp) };
-        }
+        return unsafe { (get_original_functions().mkfifo)(pathname, mode) };
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe { (get_original_functions().execve)(overlay_cstr.as_ptr(), argv, envp) };
-        }
+    match unsafe { resolve_create_path(pathname) } {
+        Resolved::Original => unsafe { (get_original_functions().mkfifo)(pathname, mode) },
+        Resolved::Redirect(upper_cstr) => unsafe {
+            (get_original_functions().mkfifo)(upper_cstr.as_ptr(), mode)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().execve)(pathname, argv, envp) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn execvp(file: *const c_char, argv: *const *const c_char) -> c_int {
+pub unsafe extern "C" fn mkfifoat(dirfd: c_int, pathname: *const c_char, mode: mode_t) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().execvp)(file, argv) };
+        return unsafe { (get_original_functions().mkfifoat)(dirfd, pathname, mode) };
     }
-    if let Some(path_str) = unsafe { cstr_to_string(file) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().execvp)(file, argv) };
-        }
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(file) } {
-        if path_str.starts_with('/') {
-            if let Some(overlay_path) = find_overlay_path(&path_str) {
-                let overlay_cstr = CString::new(overlay_path).unwrap();
-                return unsafe { (get_original_functions().execvp)(overlay_cstr.as_ptr(), argv) };
-            }
-        }
+    match unsafe { resolve_create_path_at(dirfd, pathname) } {
+        Resolved::Original => unsafe { (get_original_functions().mkfifoat)(dirfd, pathname, mode) },
+        Resolved::Redirect(upper_cstr) => unsafe {
+            (get_original_functions().mkfifoat)(dirfd, upper_cstr.as_ptr(), mode)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().execvp)(file, argv) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn execv(pathname: *const c_char, argv: *const *const c_char) -> c_int {
+pub unsafe extern "C" fn truncate(path: *const c_char, length: off_t) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().execv)(pathname, argv) };
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().execv)(pathname, argv) };
-        }
+        return unsafe { (get_original_functions().truncate)(path, length) };
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe { (get_original_functions().execv)(overlay_cstr.as_ptr(), argv) };
-        }
+    match unsafe { resolve_write_path(path, true) } {
+        Resolved::Original => unsafe { (get_original_functions().truncate)(path, length) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().truncate)(overlay_cstr.as_ptr(), length)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().execv)(pathname, argv) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn unlink(pathname: *const c_char) -> c_int {
+pub unsafe extern "C" fn utimensat(
+    dirfd: c_int,
+    pathname: *const c_char,
+    times: *const libc::timespec,
+    flags: c_int,
+) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().unlink)(pathname) };
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().unlink)(pathname) };
-        }
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe { (get_original_functions().unlink)(overlay_cstr.as_ptr()) };
-        }
+        return unsafe { (get_original_functions().utimensat)(dirfd, pathname, times, flags) };
+    }
+    // A NULL or empty (`AT_EMPTY_PATH`) path sets the times of `dirfd` itself.
+    let follow = (flags & libc::AT_SYMLINK_NOFOLLOW) == 0;
+    match unsafe { resolve_write_path_at(dirfd, pathname, follow) } {
+        Resolved::Original => unsafe {
+            (get_original_functions().utimensat)(dirfd, pathname, times, flags)
+        },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().utimensat)(dirfd, overlay_cstr.as_ptr(), times, flags)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().unlink)(pathname) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn unlinkat(dirfd: c_int, pathname: *const c_char, flags: c_int) -> c_int {
+pub unsafe extern "C" fn futimesat(
+    dirfd: c_int,
+    pathname: *const c_char,
+    times: *const libc::timeval,
+) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().unlinkat)(dirfd, pathname, flags) };
-    }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-                return unsafe { (get_original_functions().unlinkat)(dirfd, pathname, flags) };
-            }
-        }
+        return unsafe { (get_original_functions().futimesat)(dirfd, pathname, times) };
     }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if let Some(overlay_path) = find_overlay_path(&path_str) {
-                let overlay_cstr = CString::new(overlay_path).unwrap();
-                return unsafe {
-                    (get_original_functions().unlinkat)(dirfd, overlay_cstr.as_ptr(), flags)
-                };
-            }
-        }
+    match unsafe { resolve_write_path_at(dirfd, pathname, true) } {
+        Resolved::Original => unsafe {
+            (get_original_functions().futimesat)(dirfd, pathname, times)
+        },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().futimesat)(dirfd, overlay_cstr.as_ptr(), times)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().unlinkat)(dirfd, pathname, flags) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn rmdir(pathname: *const c_char) -> c_int {
+pub unsafe extern "C" fn utimes(pathname: *const c_char, times: *const libc::timeval) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().rmdir)(pathname) };
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().rmdir)(pathname) };
-        }
+        return unsafe { (get_original_functions().utimes)(pathname, times) };
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe { (get_original_functions().rmdir)(overlay_cstr.as_ptr()) };
-        }
+    match unsafe { resolve_write_path(pathname, true) } {
+        Resolved::Original => unsafe { (get_original_functions().utimes)(pathname, times) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().utimes)(overlay_cstr.as_ptr(), times)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().rmdir)(pathname) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn mkdir(pathname: *const c_char, mode: mode_t) -> c_int {
+pub unsafe extern "C" fn utime(pathname: *const c_char, times: *const libc::utimbuf) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().mkdir)(pathname, mode) };
+        return unsafe { (get_original_functions().utime)(pathname, times) };
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().mkdir)(pathname, mode) };
-        }
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe { (get_original_functions().mkdir)(overlay_cstr.as_ptr(), mode) };
-        }
+    match unsafe { resolve_write_path(pathname, true) } {
+        Resolved::Original => unsafe { (get_original_functions().utime)(pathname, times) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().utime)(overlay_cstr.as_ptr(), times)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().mkdir)(pathname, mode) }
 }
 
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn mkdirat(dirfd: c_int, pathname: *const c_char, mode: mode_t) -> c_int {
-    if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().mkdirat)(dirfd, pathname, mode) };
-    }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-                return unsafe { (get_original_functions().mkdirat)(dirfd, pathname, mode) };
-            }
-        }
-    }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if let Some(overlay_path) = find_overlay_path(&path_str) {
-                let overlay_cstr = CString::new(overlay_path).unwrap();
-                return unsafe {
-                    (get_original_functions().mkdirat)(dirfd, overlay_cstr.as_ptr(), mode)
-                };
-            }
-        }
-    }
-    unsafe { (get_original_functions().mkdirat)(dirfd, pathname, mode) }
+/// Extended attributes that hold overlay metadata, such as the opaque marker. As on overlayfs, the
+/// merged view doesn't expose them on files a layer serves. Files outside the layers keep theirs,
+/// for the tools that archive or manage real overlayfs directories.
+const PRIVATE_XATTR_PREFIX: &[u8] = b"trusted.overlay.";
+
+unsafe fn is_private_xattr(name: *const c_char) -> bool {
+    !name.is_null() && unsafe { CStr::from_ptr(name) }.to_bytes().starts_with(PRIVATE_XATTR_PREFIX)
 }
 
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn rename(oldpath: *const c_char, newpath: *const c_char) -> c_int {
-    if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().rename)(oldpath, newpath) };
-    }
-    if let Some(oldpath_str) = unsafe { cstr_to_string(oldpath) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&oldpath_str) {
-            return unsafe { (get_original_functions().rename)(oldpath, newpath) };
-        }
-    }
-    if let Some(newpath_str) = unsafe { cstr_to_string(newpath) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&newpath_str) {
-            return unsafe { (get_original_functions().rename)(oldpath, newpath) };
-        }
-    }
-    if let Some(oldpath_str) = unsafe { cstr_to_string(oldpath) } {
-        if let Some(overlay_oldpath) = find_overlay_path(&oldpath_str) {
-            let overlay_old_cstr = CString::new(overlay_oldpath).unwrap();
-            if let Some(newpath_str) = unsafe { cstr_to_string(newpath) } {
-                if let Some(overlay_newpath) = find_overlay_path(&newpath_str) {
-                    let overlay_new_cstr = CString::new(overlay_newpath).unwrap();
-                    return unsafe {
-                        (get_original_functions().rename)(
-                            overlay_old_cstr.as_ptr(),
-                            overlay_new_cstr.as_ptr(),
-                        )
-                    };
-                }
-            }
-        }
+/// Whether `resolved` sends a call into one of the layers.
+fn is_layer_redirect(resolved: &Resolved) -> bool {
+    match resolved {
+        Resolved::Redirect(target_cstr) => target_cstr
+            .to_str()
+            .is_ok_and(|target| split_overlay_path(target).is_some()),
+        _ => false,
     }
-    unsafe { (get_original_functions().rename)(oldpath, newpath) }
 }
 
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn renameat(
-    olddirfd: c_int,
-    oldpath: *const c_char,
-    newdirfd: c_int,
-    newpath: *const c_char,
-) -> c_int {
-    if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().renameat)(olddirfd, oldpath, newdirfd, newpath) };
-    }
-    if olddirfd == libc::AT_FDCWD && newdirfd == libc::AT_FDCWD {
-        if let Some(oldpath_str) = unsafe { cstr_to_string(oldpath) } {
-            if get_blacklist().lock().unwrap().is_blacklisted(&oldpath_str) {
-                return unsafe { (get_original_functions().renameat)(olddirfd, oldpath, newdirfd, newpath) };
-            }
-        }
-        if let Some(newpath_str) = unsafe { cstr_to_string(newpath) } {
-            if get_blacklist().lock().unwrap().is_blacklisted(&newpath_str) {
-                return unsafe { (get_original_functions().renameat)(olddirfd, oldpath, newdirfd, newpath) };
-            }
-        }
-    }
-    if olddirfd == libc::AT_FDCWD && newdirfd == libc::AT_FDCWD {
-        if let Some(oldpath_str) = unsafe { cstr_to_string(oldpath) } {
-            if let Some(overlay_oldpath) = find_overlay_path(&oldpath_str) {
-                let overlay_old_cstr = CString::new(overlay_oldpath).unwrap();
-                if let Some(newpath_str) = unsafe { cstr_to_string(newpath) } {
-                    if let Some(overlay_newpath) = find_overlay_path(&newpath_str) {
-                        let overlay_new_cstr = CString::new(overlay_newpath).unwrap();
-                        return unsafe {
-                            (get_original_functions().renameat)(
-                                olddirfd,
-                                overlay_old_cstr.as_ptr(),
-                                newdirfd,
-                                overlay_new_cstr.as_ptr(),
-                            )
-                        };
-                    }
-                }
-            }
-        }
-    }
-    unsafe { (get_original_functions().renameat)(olddirfd, oldpath, newdirfd, newpath) }
+/// Whether the xattr `name` must stay hidden from a call that `resolved` sends on.
+unsafe fn is_hidden_xattr(resolved: &Resolved, name: *const c_char) -> bool {
+    is_layer_redirect(resolved) && unsafe { is_private_xattr(name) }
 }
 
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn creat(pathname: *const c_char, mode: mode_t) -> c_int {
-    if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().creat)(pathname, mode) };
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().creat)(pathname, mode) };
-        }
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe { (get_original_functions().creat)(overlay_cstr.as_ptr(), mode) };
-        }
-    }
-    unsafe { (get_original_functions().creat)(pathname, mode) }
+/// Whether the xattr `name` must stay hidden from a call on `fd`.
+unsafe fn is_hidden_fd_xattr(fd: c_int, name: *const c_char) -> bool {
+    let private = unsafe { is_private_xattr(name) };
+    private && is_fd_in_layer(fd)
 }
 
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn creat64(pathname: *const c_char, mode: mode_t) -> c_int {
-    if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().creat64)(pathname, mode) };
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().creat64)(pathname, mode) };
+/// Runs the `getxattr`- or `listxattr`-style `call` with a buffer of the size it asks for, asking
+/// again if the attributes grew in between.
+fn read_xattr_buffer(call: impl Fn(*mut u8, size_t) -> ssize_t) -> Result<Vec<u8>, c_int> {
+    loop {
+        let len = call(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(last_errno());
         }
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe { (get_original_functions().creat64)(overlay_cstr.as_ptr(), mode) };
+        let mut buf = vec![0u8; len as usize];
+        let len = call(buf.as_mut_ptr(), buf.len());
+        if len >= 0 {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+        if last_errno() != libc::ERANGE {
+            return Err(last_errno());
         }
     }
-    unsafe { (get_original_functions().creat64)(pathname, mode) }
+}
+
+/// Runs the `listxattr`-style `call` and copies its names, minus the private ones, to `list` with
+/// the same buffer semantics: a `size` of 0 asks for the length, and a short buffer fails with
+/// `ERANGE`.
+unsafe fn list_public_xattrs(
+    list: *mut c_char,
+    size: size_t,
+    call: impl Fn(*mut c_char, size_t) -> ssize_t,
+) -> ssize_t {
+    let names = match read_xattr_buffer(|buf, len| call(buf as *mut c_char, len)) {
+        Ok(names) => names,
+        Err(errno) => return fail(errno) as ssize_t,
+    };
+    let public: Vec<u8> = names
+        .split_inclusive(|&b| b == 0)
+        .filter(|name| !name.starts_with(PRIVATE_XATTR_PREFIX))
+        .flatten()
+        .copied()
+        .collect();
+    if size == 0 {
+        return public.len() as ssize_t;
+    }
+    if public.len() > size {
+        return fail(libc::ERANGE) as ssize_t;
+    }
+    unsafe { std::ptr::copy_nonoverlapping(public.as_ptr(), list as *mut u8, public.len()) };
+    public.len() as ssize_t
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn chdir(path: *const c_char) -> c_int {
+pub unsafe extern "C" fn getxattr(
+    path: *const c_char,
+    name: *const c_char,
+    value: *mut libc::c_void,
+    size: size_t,
+) -> ssize_t {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().chdir)(path) };
+        return unsafe { (get_original_functions().getxattr)(path, name, value, size) };
     }
-    if let Some(path_str) = unsafe { cstr_to_string(path) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().chdir)(path) };
-        }
+    let resolved = unsafe { resolve_entry_path(path, true) };
+    if unsafe { is_hidden_xattr(&resolved, name) } {
+        return fail(libc::EOPNOTSUPP) as ssize_t;
     }
-    if let Some(path_str) = unsafe { cstr_to_string(path) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe { (get_original_functions().chdir)(overlay_cstr.as_ptr()) };
-        }
+    match resolved {
+        Resolved::Original => unsafe {
+            (get_original_functions().getxattr)(path, name, value, size)
+        },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().getxattr)(overlay_cstr.as_ptr(), name, value, size)
+        },
+        Resolved::Error(errno) => fail(errno) as ssize_t,
     }
-    unsafe { (get_original_functions().chdir)(path) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn chmod(pathname: *const c_char, mode: mode_t) -> c_int {
+pub unsafe extern "C" fn lgetxattr(
+    path: *const c_char,
+    name: *const c_char,
+    value: *mut libc::c_void,
+    size: size_t,
+) -> ssize_t {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().chmod)(pathname, mode) };
+        return unsafe { (get_original_functions().lgetxattr)(path, name, value, size) };
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().chmod)(pathname, mode) };
-        }
+    let resolved = unsafe { resolve_entry_path(path, false) };
+    if unsafe { is_hidden_xattr(&resolved, name) } {
+        return fail(libc::EOPNOTSUPP) as ssize_t;
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe { (get_original_functions().chmod)(overlay_cstr.as_ptr(), mode) };
-        }
+    match resolved {
+        Resolved::Original => unsafe {
+            (get_original_functions().lgetxattr)(path, name, value, size)
+        },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().lgetxattr)(overlay_cstr.as_ptr(), name, value, size)
+        },
+        Resolved::Error(errno) => fail(errno) as ssize_t,
     }
-    unsafe { (get_original_functions().chmod)(pathname, mode) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn fchmodat(
-    dirfd: c_int,
-    pathname: *const c_char,
-    mode: mode_t,
+pub unsafe extern "C" fn setxattr(
+    path: *const c_char,
+    name: *const c_char,
+    value: *const libc::c_void,
+    size: size_t,
     flags: c_int,
 ) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().fchmodat)(dirfd, pathname, mode, flags) };
+        return unsafe { (get_original_functions().setxattr)(path, name, value, size, flags) };
     }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-                return unsafe { (get_original_functions().fchmodat)(dirfd, pathname, mode, flags) };
-            }
-        }
+    let resolved = unsafe { resolve_write_path(path, true) };
+    if unsafe { is_hidden_xattr(&resolved, name) } {
+        return fail(libc::EOPNOTSUPP);
     }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if let Some(overlay_path) = find_overlay_path(&path_str) {
-                let overlay_cstr = CString::new(overlay_path).unwrap();
-                return unsafe {
-                    (get_original_functions().fchmodat)(dirfd, overlay_cstr.as_ptr(), mode, flags)
-                };
-            }
-        }
+    match resolved {
+        Resolved::Original => unsafe {
+            (get_original_functions().setxattr)(path, name, value, size, flags)
+        },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().setxattr)(overlay_cstr.as_ptr(), name, value, size, flags)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().fchmodat)(dirfd, pathname, mode, flags) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn chown(pathname: *const c_char, owner: uid_t, group: gid_t) -> c_int {
+pub unsafe extern "C" fn lsetxattr(
+    path: *const c_char,
+    name: *const c_char,
+    value: *const libc::c_void,
+    size: size_t,
+    flags: c_int,
+) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().chown)(pathname, owner, group) };
+        return unsafe { (get_original_functions().lsetxattr)(path, name, value, size, flags) };
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().chown)(pathname, owner, group) };
-        }
+    let resolved = unsafe { resolve_write_path(path, false) };
+    if unsafe { is_hidden_xattr(&resolved, name) } {
+        return fail(libc::EOPNOTSUPP);
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe {
-                (get_original_functions().chown)(overlay_cstr.as_ptr(), owner, group)
-            };
-        }
+    match resolved {
+        Resolved::Original => unsafe {
+            (get_original_functions().lsetxattr)(path, name, value, size, flags)
+        },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().lsetxattr)(overlay_cstr.as_ptr(), name, value, size, flags)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().chown)(pathname, owner, group) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn fchownat(
-    dirfd: c_int,
-    pathname: *const c_char,
-    owner: uid_t,
-    group: gid_t,
-    flags: c_int,
-) -> c_int {
+pub unsafe extern "C" fn listxattr(
+    path: *const c_char,
+    list: *mut c_char,
+    size: size_t,
+) -> ssize_t {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe {
-            (get_original_functions().fchownat)(
-                dirfd,
-                pathname,
-                owner,
-                group,
-                flags,
-            )
-        };
-    }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-                return unsafe {
-                    (get_original_functions().fchownat)(
-                        dirfd,
-                        pathname,
-                        owner,
-                        group,
-                        flags,
-                    )
-                };
-            }
-        }
+        return unsafe { (get_original_functions().listxattr)(path, list, size) };
+    }
+    let resolved = unsafe { resolve_entry_path(path, true) };
+    let hide_private = is_layer_redirect(&resolved);
+    let overlay_cstr = match resolved {
+        Resolved::Original => None,
+        Resolved::Redirect(overlay_cstr) => Some(overlay_cstr),
+        Resolved::Error(errno) => return fail(errno) as ssize_t,
+    };
+    let path = overlay_cstr.as_ref().map_or(path, |overlay_cstr| overlay_cstr.as_ptr());
+    if !hide_private {
+        return unsafe { (get_original_functions().listxattr)(path, list, size) };
     }
-    if dirfd == libc::AT_FDCWD {
-        if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-            if let Some(overlay_path) = find_overlay_path(&path_str) {
-                let overlay_cstr = CString::new(overlay_path).unwrap();
-                return unsafe {
-                    (get_original_functions().fchownat)(
-                        dirfd,
-                        overlay_cstr.as_ptr(),
-                        owner,
-                        group,
-                        flags,
-                    )
-                };
-            }
-        }
+    unsafe {
+        list_public_xattrs(list, size, |buf, len| {
+            (get_original_functions().listxattr)(path, buf, len)
+        })
     }
-    unsafe { (get_original_functions().fchownat)(dirfd, pathname, owner, group, flags) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn lchown(pathname: *const c_char, owner: uid_t, group: gid_t) -> c_int {
+pub unsafe extern "C" fn llistxattr(
+    path: *const c_char,
+    list: *mut c_char,
+    size: size_t,
+) -> ssize_t {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().lchown)(pathname, owner, group) };
-    }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().lchown)(pathname, owner, group) };
-        }
+        return unsafe { (get_original_functions().llistxattr)(path, list, size) };
+    }
+    let resolved = unsafe { resolve_entry_path(path, false) };
+    let hide_private = is_layer_redirect(&resolved);
+    let overlay_cstr = match resolved {
+        Resolved::Original => None,
+        Resolved::Redirect(overlay_cstr) => Some(overlay_cstr),
+        Resolved::Error(errno) => return fail(errno) as ssize_t,
+    };
+    let path = overlay_cstr.as_ref().map_or(path, |overlay_cstr| overlay_cstr.as_ptr());
+    if !hide_private {
+        return unsafe { (get_original_functions().llistxattr)(path, list, size) };
     }
-    if let Some(path_str) = unsafe { cstr_to_string(pathname) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe {
-                (get_original_functions().lchown)(overlay_cstr.as_ptr(), owner, group)
-            };
-        }
+    unsafe {
+        list_public_xattrs(list, size, |buf, len| {
+            (get_original_functions().llistxattr)(path, buf, len)
+        })
     }
-    unsafe { (get_original_functions().lchown)(pathname, owner, group) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn link(oldpath: *const c_char, newpath: *const c_char) -> c_int {
+pub unsafe extern "C" fn flistxattr(fd: c_int, list: *mut c_char, size: size_t) -> ssize_t {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().link)(oldpath, newpath) };
+        return unsafe { (get_original_functions().flistxattr)(fd, list, size) };
     }
-    if let Some(oldpath_str) = unsafe { cstr_to_string(oldpath) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&oldpath_str) {
-            return unsafe { (get_original_functions().link)(oldpath, newpath) };
-        }
+    if !is_fd_in_layer(fd) {
+        return unsafe { (get_original_functions().flistxattr)(fd, list, size) };
     }
-    if let Some(newpath_str) = unsafe { cstr_to_string(newpath) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&newpath_str) {
-            return unsafe { (get_original_functions().link)(oldpath, newpath) };
-        }
+    unsafe {
+        list_public_xattrs(list, size, |buf, len| {
+            (get_original_functions().flistxattr)(fd, buf, len)
+        })
     }
-    if let Some(oldpath_str) = unsafe { cstr_to_string(oldpath) } {
-        if let Some(overlay_oldpath) = find_overlay_path(&oldpath_str) {
-            let overlay_old_cstr = CString::new(overlay_oldpath).unwrap();
-            if let Some(newpath_str) = unsafe { cstr_to_string(newpath) } {
-                if let Some(overlay_newpath) = find_overlay_path(&newpath_str) {
-                    let overlay_new_cstr = CString::new(overlay_newpath).unwrap();
-                    return unsafe {
-                        (get_original_functions().link)(
-                            overlay_old_cstr.as_ptr(),
-                            overlay_new_cstr.as_ptr(),
-                        )
-                    };
-                }
-            }
-        }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fgetxattr(
+    fd: c_int,
+    name: *const c_char,
+    value: *mut libc::c_void,
+    size: size_t,
+) -> ssize_t {
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { (get_original_functions().fgetxattr)(fd, name, value, size) };
     }
-    unsafe { (get_original_functions().link)(oldpath, newpath) }
+    if unsafe { is_hidden_fd_xattr(fd, name) } {
+        return fail(libc::EOPNOTSUPP) as ssize_t;
+    }
+    unsafe { (get_original_functions().fgetxattr)(fd, name, value, size) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn linkat(
-    olddirfd: c_int,
-    oldpath: *const c_char,
-    newdirfd: c_int,
-    newpath: *const c_char,
+pub unsafe extern "C" fn fsetxattr(
+    fd: c_int,
+    name: *const c_char,
+    value: *const libc::c_void,
+    size: size_t,
     flags: c_int,
 ) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().linkat)(olddirfd, oldpath, newdirfd, newpath, flags) };
+        return unsafe { (get_original_functions().fsetxattr)(fd, name, value, size, flags) };
     }
-    if olddirfd == libc::AT_FDCWD && newdirfd == libc::AT_FDCWD {
-        if let Some(oldpath_str) = unsafe { cstr_to_string(oldpath) } {
-            if get_blacklist().lock().unwrap().is_blacklisted(&oldpath_str) {
-                return unsafe { (get_original_functions().linkat)(olddirfd, oldpath, newdirfd, newpath, flags) };
-            }
-        }
-        if let Some(newpath_str) = unsafe { cstr_to_string(newpath) } {
-            if get_blacklist().lock().unwrap().is_blacklisted(&newpath_str) {
-                return unsafe { (get_original_functions().linkat)(olddirfd, oldpath, newdirfd, newpath, flags) };
-            }
-        }
-    }
-    if olddirfd == libc::AT_FDCWD && newdirfd == libc::AT_FDCWD {
-        if let Some(oldpath_str) = unsafe { cstr_to_string(oldpath) } {
-            if let Some(overlay_oldpath) = find_overlay_path(&oldpath_str) {
-                let overlay_old_cstr = CString::new(overlay_oldpath).unwrap();
-                if let Some(newpath_str) = unsafe { cstr_to_string(newpath) } {
-                    if let Some(overlay_newpath) = find_overlay_path(&newpath_str) {
-                        let overlay_new_cstr = CString::new(overlay_newpath).unwrap();
-                        return unsafe {
-                            (get_original_functions().linkat)(
-                                olddirfd,
-                                overlay_old_cstr.as_ptr(),
-                                newdirfd,
-                                overlay_new_cstr.as_ptr(),
-                                flags,
-                            )
-                        };
-                    }
-                }
-            }
-        }
+    if unsafe { is_hidden_fd_xattr(fd, name) } {
+        return fail(libc::EOPNOTSUPP);
     }
-    unsafe { (get_original_functions().linkat)(olddirfd, oldpath, newdirfd, newpath, flags) }
+    unsafe { (get_original_functions().fsetxattr)(fd, name, value, size, flags) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn symlink(target: *const c_char, linkpath: *const c_char) -> c_int {
+pub unsafe extern "C" fn removexattr(path: *const c_char, name: *const c_char) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().symlink)(target, linkpath) };
+        return unsafe { (get_original_functions().removexattr)(path, name) };
     }
-    if let Some(target_str) = unsafe { cstr_to_string(target) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&target_str) {
-            return unsafe { (get_original_functions().symlink)(target, linkpath) };
-        }
-    }
-    if let Some(linkpath_str) = unsafe { cstr_to_string(linkpath) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&linkpath_str) {
-            return unsafe { (get_original_functions().symlink)(target, linkpath) };
-        }
+    let resolved = unsafe { resolve_write_path(path, true) };
+    if unsafe { is_hidden_xattr(&resolved, name) } {
+        return fail(libc::EOPNOTSUPP);
     }
-    if let Some(target_str) = unsafe { cstr_to_string(target) } {
-        if let Some(overlay_target) = find_overlay_path(&target_str) {
-            let overlay_target_cstr = CString::new(overlay_target).unwrap();
-            if let Some(linkpath_str) = unsafe { cstr_to_string(linkpath) } {
-                if let Some(overlay_linkpath) = find_overlay_path(&linkpath_str) {
-                    let overlay_link_cstr = CString::new(overlay_linkpath).unwrap();
-                    return unsafe {
-                        (get_original_functions().symlink)(
-                            overlay_target_cstr.as_ptr(),
-                            overlay_link_cstr.as_ptr(),
-                        )
-                    };
-                }
-            }
-        }
+    match resolved {
+        Resolved::Original => unsafe { (get_original_functions().removexattr)(path, name) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().removexattr)(overlay_cstr.as_ptr(), name)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().symlink)(target, linkpath) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn symlinkat(
-    target: *const c_char,
-    newdirfd: c_int,
-    linkpath: *const c_char,
-) -> c_int {
+pub unsafe extern "C" fn lremovexattr(path: *const c_char, name: *const c_char) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().symlinkat)(target, newdirfd, linkpath) };
+        return unsafe { (get_original_functions().lremovexattr)(path, name) };
     }
-    if newdirfd == libc::AT_FDCWD {
-        if let Some(target_str) = unsafe { cstr_to_string(target) } {
-            if get_blacklist().lock().unwrap().is_blacklisted(&target_str) {
-                return unsafe { (get_original_functions().symlinkat)(target, newdirfd, linkpath) };
-            }
-        }
-        if let Some(linkpath_str) = unsafe { cstr_to_string(linkpath) } {
-            if get_blacklist().lock().unwrap().is_blacklisted(&linkpath_str) {
-                return unsafe { (get_original_functions().symlinkat)(target, newdirfd, linkpath) };
-            }
-        }
+    let resolved = unsafe { resolve_write_path(path, false) };
+    if unsafe { is_hidden_xattr(&resolved, name) } {
+        return fail(libc::EOPNOTSUPP);
     }
-    if newdirfd == libc::AT_FDCWD {
-        if let Some(target_str) = unsafe { cstr_to_string(target) } {
-            if let Some(overlay_target) = find_overlay_path(&target_str) {
-                let overlay_target_cstr = CString::new(overlay_target).unwrap();
-                if let Some(linkpath_str) = unsafe { cstr_to_string(linkpath) } {
-                    if let Some(overlay_linkpath) = find_overlay_path(&linkpath_str) {
-                        let overlay_link_cstr = CString::new(overlay_linkpath).unwrap();
-                        return unsafe {
-                            (get_original_functions().symlinkat)(
-                                overlay_target_cstr.as_ptr(),
-                                newdirfd,
-                                overlay_link_cstr.as_ptr(),
-                            )
-                        };
-                    }
-                }
-            }
-        }
+    match resolved {
+        Resolved::Original => unsafe { (get_original_functions().lremovexattr)(path, name) },
+        Resolved::Redirect(overlay_cstr) => unsafe {
+            (get_original_functions().lremovexattr)(overlay_cstr.as_ptr(), name)
+        },
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().symlinkat)(target, newdirfd, linkpath) }
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn truncate(path: *const c_char, length: off_t) -> c_int {
+pub unsafe extern "C" fn fremovexattr(fd: c_int, name: *const c_char) -> c_int {
     if INIT_GUARD.load(Ordering::Relaxed) {
-        return unsafe { (get_original_functions().truncate)(path, length) };
+        return unsafe { (get_original_functions().fremovexattr)(fd, name) };
     }
-    if let Some(path_str) = unsafe { cstr_to_string(path) } {
-        if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
-            return unsafe { (get_original_functions().truncate)(path, length) };
-        }
+    if unsafe { is_hidden_fd_xattr(fd, name) } {
+        return fail(libc::EOPNOTSUPP);
     }
-    if let Some(path_str) = unsafe { cstr_to_string(path) } {
-        if let Some(overlay_path) = find_overlay_path(&path_str) {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            return unsafe { (get_original_functions().truncate)(overlay_cstr.as_ptr(), length) };
+    unsafe { (get_original_functions().fremovexattr)(fd, name) }
+}
+
+/// Connecting to a UNIX socket looks its path up like `open` does, so a socket that lives in an
+/// overlay is reachable at its merged path. Abstract and unnamed sockets are passed through.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn connect(
+    sockfd: c_int,
+    addr: *const libc::sockaddr,
+    addrlen: libc::socklen_t,
+) -> c_int {
+    let path_offset = std::mem::offset_of!(libc::sockaddr_un, sun_path);
+    if INIT_GUARD.load(Ordering::Relaxed)
+        || addr.is_null()
+        || (addrlen as usize) <= path_offset
+        || unsafe { (*addr).sa_family } != libc::AF_UNIX as libc::sa_family_t
+    {
+        return unsafe { (get_original_functions().connect)(sockfd, addr, addrlen) };
+    }
+    let sun_path = unsafe {
+        std::slice::from_raw_parts(
+            (addr as *const u8).add(path_offset),
+            (addrlen as usize - path_offset).min(std::mem::size_of::<libc::sockaddr_un>() - path_offset),
+        )
+    };
+    let path: Vec<u8> = sun_path.iter().copied().take_while(|&b| b != 0).collect();
+    if path.is_empty() {
+        return unsafe { (get_original_functions().connect)(sockfd, addr, addrlen) };
+    }
+    let path_cstr = CString::new(path).unwrap();
+    match unsafe { resolve_path(path_cstr.as_ptr()) } {
+        Resolved::Original => unsafe { (get_original_functions().connect)(sockfd, addr, addrlen) },
+        Resolved::Redirect(overlay_cstr) => {
+            let overlay_path = overlay_cstr.as_bytes_with_nul();
+            let mut overlay_addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+            if overlay_path.len() > overlay_addr.sun_path.len() {
+                return fail(libc::ENAMETOOLONG);
+            }
+            overlay_addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+            for (dst, src) in overlay_addr.sun_path.iter_mut().zip(overlay_path) {
+                *dst = *src as c_char;
+            }
+            unsafe {
+                (get_original_functions().connect)(
+                    sockfd,
+                    &overlay_addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                    (path_offset + overlay_path.len()) as libc::socklen_t,
+                )
+            }
         }
+        Resolved::Error(errno) => fail(errno),
     }
-    unsafe { (get_original_functions().truncate)(path, length) }
 }
 
 #[unsafe(no_mangle)]
@@ -1608,66 +4591,198 @@ pub unsafe extern "C" fn opendir(name: *const c_char) -> *mut libc::DIR {
     if INIT_GUARD.load(Ordering::Relaxed) {
         return unsafe { (get_original_functions().opendir)(name) };
     }
-    let path_str = match unsafe { cstr_to_string(name) } {
+    let path_str = match unsafe { virtual_path(name) } {
         Some(s) => s,
         None => return unsafe { (get_original_functions().opendir)(name) },
     };
 
+    // A symlink to a directory opens the merged view of its target.
+    let target = match resolve_overlay_symlinks(&path_str, true) {
+        Ok(target) => target,
+        Err(errno) => {
+            set_errno(errno);
+            return std::ptr::null_mut();
+        }
+    };
+    let target_cstr = CString::new(target.as_str()).unwrap();
+    let relocated = target != path_str || unsafe { is_relative_to_overlay(libc::AT_FDCWD, name) };
+    let name = if relocated { target_cstr.as_ptr() } else { name };
+    let path_str = target;
+
     if get_blacklist().lock().unwrap().is_blacklisted(&path_str) {
         return unsafe { (get_original_functions().opendir)(name) };
     }
 
-    let original_dir_ptr = unsafe { (get_original_functions().opendir)(name) };
-    let mut overlay_dir_ptrs = Vec::new();
-    let overlays = get_overlay_config();
-    for overlay in overlays {
-        let overlay_path = format!("{}{}", overlay, path_str);
-        let is_dir = OVERLAY_DISABLED.with(|disabled| {
-            *disabled.borrow_mut() = true;
-            let is_dir = Path::new(&overlay_path).is_dir();
-            *disabled.borrow_mut() = false;
-            is_dir
-        });
-        if is_dir {
-            let overlay_cstr = CString::new(overlay_path).unwrap();
-            let ptr = unsafe { (get_original_functions().opendir)(overlay_cstr.as_ptr()) };
-            if !ptr.is_null() {
-                overlay_dir_ptrs.push(ptr);
-            }
-        }
+    let (overlay_dirs, base_visible) = find_overlay_dirs(&path_str);
+    if !base_visible && overlay_dirs.is_empty() {
+        set_errno(libc::ENOENT);
+        return std::ptr::null_mut();
+    }
+    let original_dir_ptr = if base_visible {
+        unsafe { (get_original_functions().opendir)(name) }
+    } else {
+        std::ptr::null_mut()
+    };
+    let handle = if !original_dir_ptr.is_null() || overlay_dirs.is_empty() {
+        original_dir_ptr
+    } else {
+        let overlay_cstr = CString::new(overlay_dirs[0].as_str()).unwrap();
+        unsafe { (get_original_functions().opendir)(overlay_cstr.as_ptr()) }
+    };
+    if handle.is_null() {
+        return handle;
     }
+    remember_fd(unsafe { (get_original_functions().dirfd)(handle) }, path_str.clone());
+    if !overlay_dirs.is_empty() {
+        unsafe { register_overlay_dir(handle, path_str) };
+    }
+    handle
+}
 
-    if original_dir_ptr.is_null() && overlay_dir_ptrs.is_empty() {
-        return std::ptr::null_mut();
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fdopendir(fd: c_int) -> *mut libc::DIR {
+    let handle = unsafe { (get_original_functions().fdopendir)(fd) };
+    if handle.is_null() || INIT_GUARD.load(Ordering::Relaxed) {
+        return handle;
+    }
+    if let Some(path_str) = fd_virtual_path(fd) {
+        if !get_blacklist().lock().unwrap().is_blacklisted(&path_str)
+            && !find_overlay_dirs(&path_str).0.is_empty()
+        {
+            unsafe { register_overlay_dir(handle, path_str) };
+        }
     }
+    handle
+}
 
+/// Starts serving `handle` as the merged view of the directory `path`.
+unsafe fn register_overlay_dir(handle: *mut libc::DIR, path: String) {
     let overlay_dir = OverlayDir {
-        original_dir_ptr,
-        overlay_dir_ptrs: overlay_dir_ptrs.clone(),
-        seen_original_entries: HashSet::new(),
-    };
-
-    let overlay_dir_boxed = Box::new(overlay_dir);
-    let overlay_dir_ptr_raw = Box::into_raw(overlay_dir_boxed);
-    let returned_ptr = if original_dir_ptr.is_null() && !overlay_dir_ptrs.is_empty() {
-        overlay_dir_ptrs[0]
-    } else {
-        original_dir_ptr
+        handle,
+        entries: unsafe { read_merged_entries(&path) },
+        path,
+        position: 0,
+        dirent: Box::new(unsafe { std::mem::zeroed() }),
+        dirent64: Box::new(unsafe { std::mem::zeroed() }),
     };
     if is_verbose_mode_enabled() {
-        eprintln!("[DEBUG] opendir: acquiring OVERLAY_DIR_MAP lock...");
+        eprintln!("[DEBUG] register_overlay_dir: acquiring OVERLAY_DIR_MAP lock...");
     }
     get_overlay_dir_map()
         .lock()
         .unwrap()
-        .insert(DirPointer(returned_ptr), unsafe {
-            Box::from_raw(overlay_dir_ptr_raw)
-        });
+        .insert(DirPointer(handle), Box::new(overlay_dir));
     if is_verbose_mode_enabled() {
-        eprintln!("[DEBUG] opendir: OVERLAY_DIR_MAP lock acquired and released.");
+        eprintln!("[DEBUG] register_overlay_dir: OVERLAY_DIR_MAP lock acquired and released.");
+    }
+}
+
+/// Returns every overlay directory that merges into `path`, highest priority first, and whether
+/// the base directory still shows through underneath them. Merging stops at the first opaque
+/// directory, whiteout or opaque parent.
+fn find_overlay_dirs(path: &str) -> (Vec<String>, bool) {
+    let mut overlay_dirs = Vec::new();
+    for overlay in get_overlay_config() {
+        let overlay_path = format!("{}{}", overlay, path);
+        // Symlinks to directories are expanded before getting here, so only real directories
+        // merge; a symlink in a lower layer is shadowed like any other non-directory.
+        let is_dir = lstat_physical(&overlay_path)
+            .is_some_and(|st| (st.st_mode & libc::S_IFMT) == libc::S_IFDIR);
+        if is_dir {
+            let opaque = is_opaque_dir(&overlay_path) || hides_lower_layers(&overlay, path);
+            overlay_dirs.push(overlay_path);
+            if opaque {
+                return (overlay_dirs, false);
+            }
+        } else if hides_lower_layers(&overlay, path) {
+            return (overlay_dirs, false);
+        }
+    }
+    (overlay_dirs, true)
+}
+
+/// Whether the entry `name` of the overlay directory `dir` is a whiteout, returning the name it
+/// hides if so.
+fn whiteout_target(dir: &str, name: &[u8], d_type: u8) -> Option<Vec<u8>> {
+    if let Some(hidden) = name.strip_prefix(b".wh.") {
+        return Some(hidden.to_vec());
+    }
+    if d_type != libc::DT_CHR && d_type != libc::DT_UNKNOWN {
+        return None;
+    }
+    let entry = format!("{}/{}", dir, String::from_utf8_lossy(name));
+    match lstat_physical(&entry) {
+        Some(st) if is_whiteout_device(&st) => Some(name.to_vec()),
+        _ => None,
+    }
+}
+
+/// Reads the directory `path` from every layer, highest priority first, and lists each name once.
+/// Whiteouts in an overlay hide the name in the layers below it and are never listed themselves.
+unsafe fn read_merged_entries(path: &str) -> Vec<MergedEntry> {
+    let (overlay_dirs, base_visible) = find_overlay_dirs(path);
+    let mut layers: Vec<(String, Option<usize>)> = overlay_dirs
+        .into_iter()
+        .map(|dir| {
+            let layer = split_overlay_path(&dir).map(|(layer, _)| layer);
+            (dir, layer)
+        })
+        .collect();
+    if base_visible {
+        layers.push((path.to_string(), None));
+    }
+    let mut entries = Vec::new();
+    let mut seen = HashSet::new();
+    for (layer, overlay_index) in layers {
+        let is_overlay = overlay_index.is_some();
+        let layer_cstr = CString::new(layer.as_str()).unwrap();
+        let layer_ptr = unsafe { (get_original_functions().opendir)(layer_cstr.as_ptr()) };
+        if layer_ptr.is_null() {
+            continue;
+        }
+        let mut hidden = Vec::new();
+        loop {
+            let dirent64_ptr = unsafe { (get_original_functions().readdir64)(layer_ptr) };
+            if dirent64_ptr.is_null() {
+                break;
+            }
+            let dirent64 = unsafe { &*dirent64_ptr };
+            let name = unsafe { CStr::from_ptr(dirent64.d_name.as_ptr()) }.to_bytes().to_vec();
+            if is_overlay {
+                if name == OPAQUE_MARKER.as_bytes() {
+                    continue;
+                }
+                if let Some(target) = whiteout_target(&layer, &name, dirent64.d_type) {
+                    hidden.push(target);
+                    continue;
+                }
+            }
+            if seen.insert(name.clone()) {
+                let ino = match overlay_index {
+                    Some(index) if is_virtual_inodes_enabled() => virtual_ino(index, dirent64.d_ino),
+                    _ => dirent64.d_ino,
+                };
+                entries.push(MergedEntry {
+                    name,
+                    ino,
+                    d_type: dirent64.d_type,
+                });
+            }
+        }
+        unsafe { (get_original_functions().closedir)(layer_ptr) };
+        // Only the layers below see the whiteouts, whatever order readdir returned them in.
+        seen.extend(hidden);
     }
+    entries
+}
 
-    returned_ptr
+/// Copies `name` into a `d_name` buffer, truncating it if needed and always NUL-terminating.
+fn fill_d_name(d_name: &mut [c_char], name: &[u8]) {
+    let len = name.len().min(d_name.len() - 1);
+    for (dst, src) in d_name.iter_mut().zip(&name[..len]) {
+        *dst = *src as c_char;
+    }
+    d_name[len] = 0;
 }
 
 #[unsafe(no_mangle)]
@@ -1679,51 +4794,23 @@ pub unsafe extern "C" fn readdir(dirp: *mut libc::DIR) -> *mut libc::dirent {
     if is_verbose_mode_enabled() {
         eprintln!("[DEBUG] readdir: OVERLAY_DIR_MAP lock acquired.");
     }
-    let overlay_dir_opt = map.get_mut(&DirPointer(dirp));
-    if let Some(overlay_dir) = overlay_dir_opt {
-        loop {
-            for overlay_ptr in &overlay_dir.overlay_dir_ptrs {
-                let overlay_dirent_ptr = unsafe { (get_original_functions().readdir)(*overlay_ptr) };
-                if !overlay_dirent_ptr.is_null() {
-                    let overlay_dirent = unsafe { *overlay_dirent_ptr };
-                    let d_name_cstr = unsafe { CStr::from_ptr(overlay_dirent.d_name.as_ptr()) };
-                    let d_name_str = d_name_cstr.to_string_lossy().into_owned();
-                    overlay_dir.seen_original_entries.insert(d_name_str.clone());
-                    return DIRENT_BUFFER.with(|cell| {
-                        let mut dirent_buffer = cell.borrow_mut();
-                        *dirent_buffer = overlay_dirent;
-                        &mut *dirent_buffer as *mut libc::dirent
-                    });
-                }
-            }
-
-            if overlay_dir.original_dir_ptr.is_null() {
-                return std::ptr::null_mut();
-            }
-            let original_dirent_ptr =
-                unsafe { (get_original_functions().readdir)(overlay_dir.original_dir_ptr) };
-            if !original_dirent_ptr.is_null() {
-                let original_dirent = unsafe { *original_dirent_ptr };
-                let d_name_cstr = unsafe { CStr::from_ptr(original_dirent.d_name.as_ptr()) };
-                let d_name_str = d_name_cstr.to_string_lossy().into_owned();
-                if d_name_str != "."
-                    && d_name_str != ".."
-                    && !overlay_dir.seen_original_entries.contains(&d_name_str)
-                {
-                    return DIRENT_BUFFER.with(|cell| {
-                        let mut dirent_buffer = cell.borrow_mut();
-                        *dirent_buffer = original_dirent;
-                        &mut *dirent_buffer as *mut libc::dirent
-                    });
-                }
-            } else {
-                return std::ptr::null_mut();
-            }
-        }
+    if let Some(overlay_dir) = map.get_mut(&DirPointer(dirp)) {
+        let Some(entry) = overlay_dir.entries.get(overlay_dir.position) else {
+            return std::ptr::null_mut();
+        };
+        overlay_dir.position += 1;
+        let dirent = &mut *overlay_dir.dirent;
+        dirent.d_ino = entry.ino as libc::ino_t;
+        dirent.d_off = overlay_dir.position as libc::off_t;
+        dirent.d_reclen = std::mem::size_of::<libc::dirent>() as u16;
+        dirent.d_type = entry.d_type;
+        fill_d_name(&mut dirent.d_name, &entry.name);
+        return dirent as *mut libc::dirent;
     }
     if is_verbose_mode_enabled() {
         eprintln!("[DEBUG] readdir: OVERLAY_DIR_MAP lock released (implicit).");
     }
+    drop(map);
     unsafe { (get_original_functions().readdir)(dirp) }
 }
 
@@ -1736,52 +4823,23 @@ pub unsafe extern "C" fn readdir64(dirp: *mut libc::DIR) -> *mut libc::dirent64
     if is_verbose_mode_enabled() {
         eprintln!("[DEBUG] readdir64: OVERLAY_DIR_MAP lock acquired.");
     }
-    let overlay_dir_opt = map.get_mut(&DirPointer(dirp));
-    if let Some(overlay_dir) = overlay_dir_opt {
-        loop {
-            for overlay_ptr in &overlay_dir.overlay_dir_ptrs {
-                let overlay_dirent64_ptr =
-                    unsafe { (get_original_functions().readdir64)(*overlay_ptr) };
-                if !overlay_dirent64_ptr.is_null() {
-                    let overlay_dirent64 = unsafe { *overlay_dirent64_ptr };
-                    let d_name_cstr = unsafe { CStr::from_ptr(overlay_dirent64.d_name.as_ptr()) };
-                    let d_name_str = d_name_cstr.to_string_lossy().into_owned();
-                    overlay_dir.seen_original_entries.insert(d_name_str.clone());
-                    return DIRENT64_BUFFER.with(|cell| {
-                        let mut dirent64_buffer = cell.borrow_mut();
-                        *dirent64_buffer = overlay_dirent64;
-                        &mut *dirent64_buffer as *mut libc::dirent64
-                    });
-                }
-            }
-
-            if overlay_dir.original_dir_ptr.is_null() {
-                return std::ptr::null_mut();
-            }
-            let original_dirent64_ptr =
-                unsafe { (get_original_functions().readdir64)(overlay_dir.original_dir_ptr) };
-            if !original_dirent64_ptr.is_null() {
-                let original_dirent64 = unsafe { *original_dirent64_ptr };
-                let d_name_cstr = unsafe { CStr::from_ptr(original_dirent64.d_name.as_ptr()) };
-                let d_name_str = d_name_cstr.to_string_lossy().into_owned();
-                if d_name_str != "."
-                    && d_name_str != ".."
-                    && !overlay_dir.seen_original_entries.contains(&d_name_str)
-                {
-                    return DIRENT64_BUFFER.with(|cell| {
-                        let mut dirent64_buffer = cell.borrow_mut();
-                        *dirent64_buffer = original_dirent64;
-                        &mut *dirent64_buffer as *mut libc::dirent64
-                    });
-                }
-            } else {
-                return std::ptr::null_mut();
-            }
-        }
+    if let Some(overlay_dir) = map.get_mut(&DirPointer(dirp)) {
+        let Some(entry) = overlay_dir.entries.get(overlay_dir.position) else {
+            return std::ptr::null_mut();
+        };
+        overlay_dir.position += 1;
+        let dirent64 = &mut *overlay_dir.dirent64;
+        dirent64.d_ino = entry.ino as libc::ino64_t;
+        dirent64.d_off = overlay_dir.position as libc::off64_t;
+        dirent64.d_reclen = std::mem::size_of::<libc::dirent64>() as u16;
+        dirent64.d_type = entry.d_type;
+        fill_d_name(&mut dirent64.d_name, &entry.name);
+        return dirent64 as *mut libc::dirent64;
     }
     if is_verbose_mode_enabled() {
         eprintln!("[DEBUG] readdir64: OVERLAY_DIR_MAP lock released (implicit).");
     }
+    drop(map);
     unsafe { (get_original_functions().readdir64)(dirp) }
 }
 
@@ -1790,39 +4848,519 @@ pub unsafe extern "C" fn closedir(dirp: *mut libc::DIR) -> c_int {
     if is_verbose_mode_enabled() {
         eprintln!("[DEBUG] closedir: acquiring OVERLAY_DIR_MAP lock...");
     }
-    let mut map = get_overlay_dir_map().lock().unwrap();
+    let removed = get_overlay_dir_map().lock().unwrap().remove(&DirPointer(dirp));
     if is_verbose_mode_enabled() {
-        eprintln!("[DEBUG] closedir: OVERLAY_DIR_MAP lock acquired.");
+        eprintln!("[DEBUG] closedir: OVERLAY_DIR_MAP lock released.");
     }
-    if let Some(overlay_dir) = map.remove(&DirPointer(dirp)) {
-        let original_res = if !overlay_dir.original_dir_ptr.is_null() {
-            unsafe { (get_original_functions().closedir)(overlay_dir.original_dir_ptr) }
-        } else {
-            0
+    forget_fd(unsafe { (get_original_functions().dirfd)(dirp) });
+    match removed {
+        Some(overlay_dir) => unsafe { (get_original_functions().closedir)(overlay_dir.handle) },
+        None => unsafe { (get_original_functions().closedir)(dirp) },
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rewinddir(dirp: *mut libc::DIR) {
+    let path = match get_overlay_dir_map().lock().unwrap().get(&DirPointer(dirp)) {
+        Some(overlay_dir) => overlay_dir.path.clone(),
+        None => return unsafe { (get_original_functions().rewinddir)(dirp) },
+    };
+    // Like a real rewind, pick up entries created or removed since the stream was opened.
+    let entries = unsafe { read_merged_entries(&path) };
+    if let Some(overlay_dir) = get_overlay_dir_map().lock().unwrap().get_mut(&DirPointer(dirp)) {
+        overlay_dir.entries = entries;
+        overlay_dir.position = 0;
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn telldir(dirp: *mut libc::DIR) -> libc::c_long {
+    if let Some(overlay_dir) = get_overlay_dir_map().lock().unwrap().get(&DirPointer(dirp)) {
+        return overlay_dir.position as libc::c_long;
+    }
+    unsafe { (get_original_functions().telldir)(dirp) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn seekdir(dirp: *mut libc::DIR, loc: libc::c_long) {
+    if let Some(overlay_dir) = get_overlay_dir_map().lock().unwrap().get_mut(&DirPointer(dirp)) {
+        overlay_dir.position = loc.max(0) as usize;
+        return;
+    }
+    unsafe { (get_original_functions().seekdir)(dirp, loc) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dirfd(dirp: *mut libc::DIR) -> c_int {
+    let fd = unsafe { (get_original_functions().dirfd)(dirp) };
+    if fd < 0 {
+        return fd;
+    }
+    // The fd belongs to one layer only; make sure *at() calls through it see the merged view.
+    let path = get_overlay_dir_map()
+        .lock()
+        .unwrap()
+        .get(&DirPointer(dirp))
+        .map(|overlay_dir| overlay_dir.path.clone());
+    if let Some(path) = path {
+        remember_fd(fd, path);
+    }
+    fd
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn close(fd: c_int) -> c_int {
+    forget_fd(fd);
+    if let Some(fd_listings) = FD_LISTINGS.get() {
+        fd_listings.lock().unwrap().remove(&fd);
+    }
+    unsafe { (get_original_functions().close)(fd) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dup(oldfd: c_int) -> c_int {
+    let newfd = unsafe { (get_original_functions().dup)(oldfd) };
+    if newfd >= 0 {
+        copy_fd_path(oldfd, newfd);
+    }
+    newfd
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dup2(oldfd: c_int, newfd: c_int) -> c_int {
+    let result = unsafe { (get_original_functions().dup2)(oldfd, newfd) };
+    if result >= 0 && oldfd != newfd {
+        copy_fd_path(oldfd, newfd);
+    }
+    result
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dup3(oldfd: c_int, newfd: c_int, flags: c_int) -> c_int {
+    let result = unsafe { (get_original_functions().dup3)(oldfd, newfd, flags) };
+    if result >= 0 {
+        copy_fd_path(oldfd, newfd);
+    }
+    result
+}
+
+/// Fills `buf` with `linux_dirent64` records for the merged view of the directory behind `fd`,
+/// continuing where the previous call left off. `None` means the directory isn't merged and the
+/// kernel should list it.
+fn merged_getdents64(fd: c_int, buf: *mut u8, count: usize) -> Option<c_long> {
+    let st = fd_identity(fd)?;
+    if (st.st_mode & libc::S_IFMT) != libc::S_IFDIR {
+        return None;
+    }
+    let mut fd_listings = FD_LISTINGS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap();
+    let current = fd_listings
+        .get(&fd)
+        .is_some_and(|listing| listing.dev == st.st_dev && listing.ino == st.st_ino);
+    if !current {
+        fd_listings.remove(&fd);
+        let path = fd_virtual_path(fd)?;
+        if get_blacklist().lock().unwrap().is_blacklisted(&path)
+            || find_overlay_dirs(&path).0.is_empty()
+        {
+            return None;
+        }
+        let listing = FdListing {
+            dev: st.st_dev,
+            ino: st.st_ino,
+            entries: unsafe { read_merged_entries(&path) },
+            position: 0,
         };
-        let mut overlay_res = 0;
-        for ptr in overlay_dir.overlay_dir_ptrs {
-            if !ptr.is_null() {
-                let res = unsafe { (get_original_functions().closedir)(ptr) };
-                if res != 0 {
-                    overlay_res = res;
-                }
+        fd_listings.insert(fd, listing);
+    }
+    let listing = fd_listings.get_mut(&fd)?;
+    let mut written = 0;
+    while let Some(entry) = listing.entries.get(listing.position) {
+        let record = dirent64_record(entry, listing.position as i64 + 1);
+        if written + record.len() > count {
+            if written == 0 {
+                return Some(fail(libc::EINVAL) as c_long);
             }
+            break;
         }
-        if original_res == 0 && overlay_res == 0 {
-            if is_verbose_mode_enabled() {
-                eprintln!("[DEBUG] closedir: OVERLAY_DIR_MAP lock released (implicit).");
+        listing.position += 1;
+        unsafe { std::ptr::copy_nonoverlapping(record.as_ptr(), buf.add(written), record.len()) };
+        written += record.len();
+    }
+    Some(written as c_long)
+}
+
+/// Encodes `entry` as a `linux_dirent64` record with `offset` as its `d_off`: `d_ino`, `d_off`,
+/// `d_reclen` and `d_type` precede the NUL-terminated name, and records are 8-aligned.
+fn dirent64_record(entry: &MergedEntry, offset: i64) -> Vec<u8> {
+    const NAME_OFFSET: usize = 19;
+    let reclen = (NAME_OFFSET + entry.name.len() + 1 + 7) & !7;
+    let mut record = Vec::with_capacity(reclen);
+    record.extend_from_slice(&entry.ino.to_ne_bytes());
+    record.extend_from_slice(&offset.to_ne_bytes());
+    record.extend_from_slice(&(reclen as u16).to_ne_bytes());
+    record.push(entry.d_type);
+    record.extend_from_slice(&entry.name);
+    record.resize(reclen, 0);
+    record
+}
+
+/// System call number of `fchmodat2`, which is the same on every architecture but missing from
+/// `libc` for most of them.
+const SYS_FCHMODAT2: c_long = 452;
+
+/// `syscall` is variadic in C. On the supported ABIs variadic integer and pointer arguments are
+/// passed like fixed ones, so this takes the six a system call can have; any the caller didn't
+/// pass are never used.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn syscall(
+    number: c_long,
+    a1: c_long,
+    a2: c_long,
+    a3: c_long,
+    a4: c_long,
+    a5: c_long,
+    a6: c_long,
+) -> c_long {
+    let original = get_original_functions().syscall;
+    if INIT_GUARD.load(Ordering::Relaxed) {
+        return unsafe { original(number, a1, a2, a3, a4, a5, a6) };
+    }
+    if is_verbose_mode_enabled() {
+        eprintln!("[DEBUG] syscall: number {}", number);
+    }
+    let fd = a1 as c_int;
+    let path = a2 as *const c_char;
+    // The raw calls report errors like their libc wrappers, as -1 with errno set.
+    unsafe {
+        match number {
+            libc::SYS_openat => openat(fd, path, a3 as c_int, a4 as mode_t) as c_long,
+            libc::SYS_openat2 => {
+                openat2(fd, path, a3 as *mut libc::open_how, a4 as size_t) as c_long
             }
-            return 0;
-        } else {
-            if is_verbose_mode_enabled() {
-                eprintln!("[DEBUG] closedir: OVERLAY_DIR_MAP lock released (implicit).");
+            #[cfg(target_pointer_width = "64")]
+            libc::SYS_newfstatat => fstatat(fd, path, a3 as *mut libc::stat, a4 as c_int) as c_long,
+            // 32-bit kernels fill the `struct stat64` layout here instead.
+            #[cfg(any(target_arch = "x86", target_arch = "arm"))]
+            libc::SYS_fstatat64 => {
+                fstatat64(fd, path, a3 as *mut libc::stat64, a4 as c_int) as c_long
+            }
+            libc::SYS_statx => {
+                statx(fd, path, a3 as c_int, a4 as c_uint, a5 as *mut libc::statx) as c_long
+            }
+            libc::SYS_faccessat => faccessat(fd, path, a3 as c_int, 0) as c_long,
+            libc::SYS_faccessat2 => faccessat(fd, path, a3 as c_int, a4 as c_int) as c_long,
+            libc::SYS_readlinkat => readlinkat(fd, path, a3 as *mut c_char, a4 as size_t) as c_long,
+            libc::SYS_execve => execve(
+                a1 as *const c_char,
+                a2 as *const *const c_char,
+                a3 as *const *const c_char,
+            ) as c_long,
+            libc::SYS_execveat => {
+                // Executing `dirfd` itself or refusing a final symlink is left to the kernel.
+                let Some(pathname) = virtual_path_at(fd, path).filter(|_| a5 == 0) else {
+                    return original(number, a1, a2, a3, a4, a5, a6);
+                };
+                let pathname = CString::new(pathname).unwrap();
+                execve(
+                    pathname.as_ptr(),
+                    a3 as *const *const c_char,
+                    a4 as *const *const c_char,
+                ) as c_long
+            }
+            libc::SYS_mkdirat => mkdirat(fd, path, a3 as mode_t) as c_long,
+            libc::SYS_mknodat => mknodat(fd, path, a3 as mode_t, a4 as libc::dev_t) as c_long,
+            libc::SYS_unlinkat => unlinkat(fd, path, a3 as c_int) as c_long,
+            libc::SYS_renameat2 => {
+                renameat2(fd, path, a3 as c_int, a4 as *const c_char, a5 as c_uint) as c_long
+            }
+            libc::SYS_linkat => {
+                linkat(fd, path, a3 as c_int, a4 as *const c_char, a5 as c_int) as c_long
+            }
+            libc::SYS_symlinkat => {
+                symlinkat(a1 as *const c_char, a2 as c_int, a3 as *const c_char) as c_long
+            }
+            libc::SYS_fchmodat => fchmodat(fd, path, a3 as mode_t, 0) as c_long,
+            // glibc's fchmodat only knows `AT_SYMLINK_NOFOLLOW`, so fchmodat2 goes to the kernel
+            // with the resolved path, which also keeps its `ENOSYS` on older kernels.
+            SYS_FCHMODAT2 => {
+                let follow = (a4 as c_int & libc::AT_SYMLINK_NOFOLLOW) == 0;
+                match resolve_write_path_at(fd, path, follow) {
+                    Resolved::Original => original(number, a1, a2, a3, a4, a5, a6),
+                    Resolved::Redirect(overlay_cstr) => {
+                        original(number, a1, overlay_cstr.as_ptr() as c_long, a3, a4, a5, a6)
+                    }
+                    Resolved::Error(errno) => fail(errno) as c_long,
+                }
+            }
+            libc::SYS_fchownat => {
+                fchownat(fd, path, a3 as uid_t, a4 as gid_t, a5 as c_int) as c_long
+            }
+            libc::SYS_truncate => truncate(a1 as *const c_char, a2 as off_t) as c_long,
+            libc::SYS_getxattr => getxattr(
+                a1 as *const c_char,
+                a2 as *const c_char,
+                a3 as *mut libc::c_void,
+                a4 as size_t,
+            ) as c_long,
+            libc::SYS_lgetxattr => lgetxattr(
+                a1 as *const c_char,
+                a2 as *const c_char,
+                a3 as *mut libc::c_void,
+                a4 as size_t,
+            ) as c_long,
+            libc::SYS_setxattr => setxattr(
+                a1 as *const c_char,
+                a2 as *const c_char,
+                a3 as *const libc::c_void,
+                a4 as size_t,
+                a5 as c_int,
+            ) as c_long,
+            libc::SYS_lsetxattr => lsetxattr(
+                a1 as *const c_char,
+                a2 as *const c_char,
+                a3 as *const libc::c_void,
+                a4 as size_t,
+                a5 as c_int,
+            ) as c_long,
+            libc::SYS_fgetxattr => fgetxattr(
+                fd,
+                a2 as *const c_char,
+                a3 as *mut libc::c_void,
+                a4 as size_t,
+            ) as c_long,
+            libc::SYS_fsetxattr => fsetxattr(
+                fd,
+                a2 as *const c_char,
+                a3 as *const libc::c_void,
+                a4 as size_t,
+                a5 as c_int,
+            ) as c_long,
+            libc::SYS_listxattr => {
+                listxattr(a1 as *const c_char, a2 as *mut c_char, a3 as size_t) as c_long
+            }
+            libc::SYS_llistxattr => {
+                llistxattr(a1 as *const c_char, a2 as *mut c_char, a3 as size_t) as c_long
+            }
+            libc::SYS_flistxattr => flistxattr(fd, a2 as *mut c_char, a3 as size_t) as c_long,
+            libc::SYS_removexattr => {
+                removexattr(a1 as *const c_char, a2 as *const c_char) as c_long
+            }
+            libc::SYS_lremovexattr => {
+                lremovexattr(a1 as *const c_char, a2 as *const c_char) as c_long
+            }
+            libc::SYS_fremovexattr => fremovexattr(fd, a2 as *const c_char) as c_long,
+            libc::SYS_utimensat => {
+                utimensat(fd, path, a3 as *const libc::timespec, a4 as c_int) as c_long
             }
-            return -1;
+            libc::SYS_chdir => chdir(a1 as *const c_char) as c_long,
+            libc::SYS_fchdir => fchdir(fd) as c_long,
+            libc::SYS_getcwd if a1 != 0 => {
+                // The raw call returns the length of the path including its NUL.
+                let buf = a1 as *mut c_char;
+                if getcwd(buf, a2 as size_t).is_null() {
+                    return -1;
+                }
+                (CStr::from_ptr(buf).to_bytes().len() + 1) as c_long
+            }
+            libc::SYS_getdents64 => match merged_getdents64(fd, a2 as *mut u8, a3 as usize) {
+                Some(result) => result,
+                None => original(number, a1, a2, a3, a4, a5, a6),
+            },
+            libc::SYS_close => close(fd) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_open => open(a1 as *const c_char, a2 as c_int, a3 as mode_t) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_creat => creat(a1 as *const c_char, a2 as mode_t) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_stat => stat(a1 as *const c_char, a2 as *mut libc::stat) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_lstat => lstat(a1 as *const c_char, a2 as *mut libc::stat) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_access => access(a1 as *const c_char, a2 as c_int) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_readlink => {
+                readlink(a1 as *const c_char, a2 as *mut c_char, a3 as size_t) as c_long
+            }
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_mkdir => mkdir(a1 as *const c_char, a2 as mode_t) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_mknod => {
+                mknod(a1 as *const c_char, a2 as mode_t, a3 as libc::dev_t) as c_long
+            }
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_rmdir => rmdir(a1 as *const c_char) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_unlink => unlink(a1 as *const c_char) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_rename => rename(a1 as *const c_char, a2 as *const c_char) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_renameat => renameat(fd, path, a3 as c_int, a4 as *const c_char) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_link => link(a1 as *const c_char, a2 as *const c_char) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_symlink => symlink(a1 as *const c_char, a2 as *const c_char) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_chmod => chmod(a1 as *const c_char, a2 as mode_t) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_chown => chown(a1 as *const c_char, a2 as uid_t, a3 as gid_t) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_futimesat => futimesat(fd, path, a3 as *const libc::timeval) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_utimes => utimes(a1 as *const c_char, a2 as *const libc::timeval) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_utime => utime(a1 as *const c_char, a2 as *const libc::utimbuf) as c_long,
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_lchown => lchown(a1 as *const c_char, a2 as uid_t, a3 as gid_t) as c_long,
+            _ => original(number, a1, a2, a3, a4, a5, a6),
         }
     }
-    if is_verbose_mode_enabled() {
-        eprintln!("[DEBUG] closedir: OVERLAY_DIR_MAP lock released (implicit).");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn openat2_beneath_rejects_dotdot_that_leaves_dir() {
+        let dir = "/obsidianos-overlays-test/dir";
+        assert_eq!(
+            resolve_openat2_path(dir, "sub/../../dir/file", libc::RESOLVE_BENEATH, true),
+            Err(libc::EXDEV)
+        );
+        assert_eq!(
+            resolve_openat2_path(dir, "..", libc::RESOLVE_BENEATH, true),
+            Err(libc::EXDEV)
+        );
+        assert_eq!(
+            resolve_openat2_path(dir, "sub/../file", libc::RESOLVE_BENEATH, true),
+            Ok((format!("{}/file", dir), false))
+        );
+        assert_eq!(
+            resolve_openat2_path(dir, "sub/../../dir/file", 0, true),
+            Ok((format!("{}/file", dir), false))
+        );
+    }
+
+    #[test]
+    fn openat2_in_root_keeps_lookups_under_dir() {
+        let dir = "/obsidianos-overlays-test/root";
+        assert_eq!(
+            resolve_openat2_path(dir, "/etc/passwd", libc::RESOLVE_IN_ROOT, true),
+            Ok((format!("{}/etc/passwd", dir), false))
+        );
+        assert_eq!(
+            resolve_openat2_path(dir, "../../etc", libc::RESOLVE_IN_ROOT, true),
+            Ok((format!("{}/etc", dir), false))
+        );
+        assert_eq!(
+            resolve_openat2_path(dir, "/..", libc::RESOLVE_IN_ROOT, true),
+            Ok((dir.to_string(), false))
+        );
+    }
+
+    #[test]
+    fn normalize_path_collapses_lexically() {
+        assert_eq!(normalize_path_with("//usr//bin/", false), "/usr/bin");
+        assert_eq!(normalize_path_with("/..", false), "/");
+        assert_eq!(normalize_path_with("/usr/../../bin", false), "/bin");
+        assert_eq!(normalize_path_with("/usr/bin/.", false), "/usr/bin");
+        assert_eq!(normalize_path_with("/usr/./lib/../bin", false), "/usr/bin");
+        assert_eq!(normalize_path_with("/", false), "/");
+    }
+
+    #[test]
+    fn normalize_path_expands_symlink_before_dotdot() {
+        let dir = env::temp_dir().join(format!("obsidianos-overlays-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("real/sub")).unwrap();
+        std::os::unix::fs::symlink("real/sub", dir.join("link")).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+        let physical = normalize_path_with(&format!("{}/link/../x", dir), true);
+        let lexical = normalize_path_with(&format!("{}/link/../x", dir), false);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(physical, format!("{}/real/x", dir));
+        assert_eq!(lexical, format!("{}/x", dir));
+    }
+
+    #[test]
+    fn join_under_root_stays_under_root() {
+        assert_eq!(join_under_root("/", "a/../b"), "/b");
+        assert_eq!(join_under_root("/r", "/../x"), "/r/x");
+        assert_eq!(join_under_root("/r", "../../x/./y"), "/r/x/y");
+        assert_eq!(join_under_root("/r", "/"), "/r");
+        assert_eq!(join_under_root("/r", ""), "/r");
+    }
+
+    #[test]
+    fn has_trailing_slash_ignores_root() {
+        unsafe {
+            assert!(has_trailing_slash(c"/usr/".as_ptr()));
+            assert!(has_trailing_slash(c"/usr/.".as_ptr()));
+            assert!(has_trailing_slash(c"usr/".as_ptr()));
+            assert!(!has_trailing_slash(c"/usr".as_ptr()));
+            assert!(!has_trailing_slash(c"/".as_ptr()));
+            assert!(!has_trailing_slash(c"/.".as_ptr()));
+            assert!(!has_trailing_slash(std::ptr::null()));
+        }
+    }
+
+    #[test]
+    fn whiteout_target_reads_wh_names() {
+        let dir = "/obsidianos-overlays-test";
+        assert_eq!(whiteout_target(dir, b".wh.foo", libc::DT_REG), Some(b"foo".to_vec()));
+        assert_eq!(whiteout_target(dir, b"foo", libc::DT_REG), None);
+        assert_eq!(whiteout_target(dir, b"foo", libc::DT_DIR), None);
+        // A character device that doesn't exist can't be a whiteout.
+        assert_eq!(whiteout_target(dir, b"foo", libc::DT_CHR), None);
+    }
+
+    #[test]
+    fn dirent64_record_layout() {
+        let entry = MergedEntry {
+            name: b"hello".to_vec(),
+            ino: 0x1122_3344_5566_7788,
+            d_type: libc::DT_REG,
+        };
+        let record = dirent64_record(&entry, 3);
+        assert_eq!(record.len(), 32);
+        assert_eq!(u64::from_ne_bytes(record[0..8].try_into().unwrap()), entry.ino);
+        assert_eq!(i64::from_ne_bytes(record[8..16].try_into().unwrap()), 3);
+        assert_eq!(u16::from_ne_bytes(record[16..18].try_into().unwrap()), 32);
+        assert_eq!(record[18], libc::DT_REG);
+        assert_eq!(&record[19..24], b"hello");
+        assert!(record[24..].iter().all(|&b| b == 0));
+
+        // The name and its NUL fill the record exactly, so no padding is added.
+        let entry = MergedEntry {
+            name: b"abcd".to_vec(),
+            ino: 1,
+            d_type: libc::DT_DIR,
+        };
+        assert_eq!(dirent64_record(&entry, 1).len(), 24);
+    }
+
+    #[test]
+    fn openat2_confines_base_symlinks() {
+        let dir = env::temp_dir().join(format!("obsidianos-overlays-openat2-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        std::os::unix::fs::symlink("../../etc", dir.join("sub/up")).unwrap();
+        std::os::unix::fs::symlink("/etc", dir.join("abs")).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+        let beneath = resolve_openat2_path(&dir, "sub/up/passwd", libc::RESOLVE_BENEATH, true);
+        let in_root = resolve_openat2_path(&dir, "abs/passwd", libc::RESOLVE_IN_ROOT, true);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(beneath, Err(libc::EXDEV));
+        assert_eq!(in_root, Ok((format!("{}/etc/passwd", dir), true)));
+    }
+
+    #[test]
+    fn climbs_out_tracks_depth() {
+        assert!(climbs_out("..", 0));
+        assert!(climbs_out("a/../../b", 0));
+        assert!(!climbs_out("a/./../b", 0));
+        assert!(!climbs_out("../x", 1));
+        assert!(climbs_out("../../x", 1));
     }
-    unsafe { (get_original_functions().closedir)(dirp) }
 }